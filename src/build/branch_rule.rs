@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+
+use anyhow::Context;
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+
+use crate::config::{BranchAndRelease, BranchPattern};
+use crate::template;
+
+enum Matcher {
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+/// Builds every branch whose name matches a glob or a regex, with a release & asset names
+/// derived from the branch's name
+pub struct BranchRule {
+    matcher: Matcher,
+    cfg: BranchPattern,
+}
+
+impl BranchRule {
+    pub fn new(cfg: BranchPattern) -> anyhow::Result<Self> {
+        let matcher = match (&cfg.glob, &cfg.regex) {
+            (Some(glob), _) => Matcher::Glob(
+                GlobBuilder::new(glob)
+                    .literal_separator(true)
+                    .build()
+                    .context(format!("Invalid branch glob {glob}"))?
+                    .compile_matcher(),
+            ),
+            (None, Some(regex)) => {
+                Matcher::Regex(Regex::new(regex).context(format!("Invalid branch regex {regex}"))?)
+            }
+            (None, None) => unreachable!("validated when reading the config"),
+        };
+
+        Ok(Self { matcher, cfg })
+    }
+
+    pub fn matches(&self, branch: &str) -> bool {
+        match &self.matcher {
+            Matcher::Glob(glob) => glob.is_match(branch),
+            Matcher::Regex(regex) => regex.is_match(branch),
+        }
+    }
+
+    /// Returns the branch with the release templates filled in
+    pub fn branch(&self, branch: &str) -> BranchAndRelease {
+        BranchAndRelease {
+            name: branch.to_string(),
+            release_id: None,
+            release_tag: Some(template::render(&self.cfg.release_tag, &vars(branch))),
+            prerelease: self.cfg.prerelease,
+            move_tag: self.cfg.move_tag,
+            release_notes: self.cfg.release_notes,
+            release_name: self.cfg.release_name.clone(),
+            release_body: self.cfg.release_body.clone(),
+        }
+    }
+
+    /// Returns the name a build config's asset is uploaded as for the branch
+    pub fn asset_name(&self, branch: &str, asset_name: &str) -> String {
+        match &self.cfg.asset_name {
+            Some(template) => {
+                let mut vars = vars(branch);
+                vars.insert("asset_name", asset_name.to_string());
+                template::render(template, &vars)
+            }
+            None => asset_name.to_string(),
+        }
+    }
+}
+
+fn vars(branch: &str) -> HashMap<&'static str, String> {
+    HashMap::from([
+        ("branch", branch.to_string()),
+        ("branch_slug", slug(branch)),
+    ])
+}
+
+/// Replaces everything but letters, digits, `.`, `_` & `-` with `-`, e.g. "release/2.4" becomes
+/// "release-2.4", so the branch name can be used in tags & file names
+fn slug(branch: &str) -> String {
+    branch
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => c,
+            _ => '-',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(glob: Option<&str>, regex: Option<&str>) -> BranchPattern {
+        BranchPattern {
+            glob: glob.map(String::from),
+            regex: regex.map(String::from),
+            release_tag: "nightly-{branch_slug}".to_string(),
+            asset_name: Some("{branch_slug}-{asset_name}".to_string()),
+            prerelease: Some(true),
+            move_tag: None,
+            release_notes: None,
+            release_name: None,
+            release_body: None,
+        }
+    }
+
+    #[test]
+    fn matches_globs_and_regexes() {
+        let rule = BranchRule::new(pattern(Some("release/*"), None)).unwrap();
+        assert!(rule.matches("release/2.4"));
+        assert!(!rule.matches("release/2.4/fix"));
+        assert!(!rule.matches("master"));
+
+        let rule = BranchRule::new(pattern(None, Some(r"^bugfix-\d+$"))).unwrap();
+        assert!(rule.matches("bugfix-123"));
+        assert!(!rule.matches("bugfix-abc"));
+    }
+
+    #[test]
+    fn fills_in_templates() {
+        let rule = BranchRule::new(pattern(Some("release/*"), None)).unwrap();
+
+        let branch = rule.branch("release/2.4");
+        assert_eq!(branch.name, "release/2.4");
+        assert_eq!(branch.release_tag.as_deref(), Some("nightly-release-2.4"));
+        assert_eq!(
+            rule.asset_name("release/2.4", "Chatterino.dmg"),
+            "release-2.4-Chatterino.dmg"
+        );
+    }
+}
@@ -0,0 +1,350 @@
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use tracing::log::*;
+
+use super::{diagnostics::Diagnostic, BuildRecord, BuildStatus};
+use crate::github::{
+    model::{CheckRunAnnotation, CheckRunOutput, CheckRunUpdate},
+    GithubClient,
+};
+
+// GitHub rejects check run summaries & texts longer than this
+const MAX_OUTPUT_LENGTH: usize = 65535;
+
+// GitHub accepts at most this many annotations per request
+const MAX_ANNOTATIONS: usize = 50;
+
+/// A GitHub Check Run that shows the progress & outcome of a build on the built commit.
+/// Failing to update the check run doesn't fail the build, errors are only logged.
+/// If it's dropped before it's finished, e.g. because a newer build aborted the build, it's
+/// completed as cancelled
+pub struct CheckRun {
+    github_client: GithubClient,
+    id: i64,
+
+    // Set once the check run is completed
+    finished: AtomicBool,
+}
+
+impl CheckRun {
+    /// Creates an in progress check run, or returns None if that fails
+    pub async fn start(github_client: &GithubClient, name: &str, head_sha: &str) -> Option<Self> {
+        match github_client.create_check_run(name, head_sha).await {
+            Ok(check_run) => Some(Self {
+                github_client: github_client.clone(),
+                id: check_run.id,
+                finished: AtomicBool::new(false),
+            }),
+            Err(e) => {
+                warn!("Failed creating check run {name}: {e}");
+                None
+            }
+        }
+    }
+
+    async fn update(&self, update: CheckRunUpdate) {
+        if let Err(e) = self.github_client.update_check_run(self.id, &update).await {
+            warn!("Failed updating check run {}: {e}", self.id);
+        }
+    }
+
+    /// Shows what the build is currently doing
+    pub async fn progress(&self, title: String, summary: String) {
+        self.update(CheckRunUpdate {
+            output: Some(CheckRunOutput {
+                title,
+                summary,
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Completes the check run with a summary of the finished build
+    pub async fn finish(&self, record: &BuildRecord, repo_dir: &Path, build_dir: &Path) {
+        self.finished.store(true, Ordering::Relaxed);
+
+        let (conclusion, title) = match (record.status, &record.error) {
+            (BuildStatus::Succeeded, _) => ("success", "Build succeeded".to_string()),
+            (BuildStatus::Skipped, _) => ("skipped", "Build skipped".to_string()),
+            (_, Some(e)) => (
+                "failure",
+                match e.step {
+                    Some(step) => format!("Build failed in step {step}"),
+                    None => format!("Build failed in the {} stage", e.stage),
+                },
+            ),
+            (_, None) => ("failure", "Build failed".to_string()),
+        };
+
+        let (text, annotations) = match &record.error {
+            Some(e) => (
+                (!e.output.is_empty()).then(|| log_tail(&e.output)),
+                e.diagnostics
+                    .iter()
+                    .filter_map(|d| annotation(d, repo_dir, build_dir))
+                    .take(MAX_ANNOTATIONS)
+                    .collect(),
+            ),
+            None => (None, vec![]),
+        };
+
+        self.update(CheckRunUpdate {
+            status: Some("completed".to_string()),
+            conclusion: Some(conclusion.to_string()),
+            output: Some(CheckRunOutput {
+                title,
+                summary: truncate_start(summary(record), MAX_OUTPUT_LENGTH),
+                text,
+                annotations,
+            }),
+        })
+        .await
+    }
+}
+
+impl Drop for CheckRun {
+    fn drop(&mut self) {
+        if self.finished.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            warn!("Unable to cancel check run {}, no runtime", self.id);
+            return;
+        };
+
+        let (github_client, id) = (self.github_client.clone(), self.id);
+        runtime.spawn(async move {
+            let update = CheckRunUpdate {
+                status: Some("completed".to_string()),
+                conclusion: Some("cancelled".to_string()),
+                output: Some(CheckRunOutput {
+                    title: "Build cancelled".to_string(),
+                    summary: "The build was aborted, e.g. because a newer build started"
+                        .to_string(),
+                    ..Default::default()
+                }),
+            };
+
+            if let Err(e) = github_client.update_check_run(id, &update).await {
+                warn!("Failed cancelling check run {id}: {e}");
+            }
+        });
+    }
+}
+
+/// Renders the outcome, step timings & artifact size of the build as markdown
+fn summary(record: &BuildRecord) -> String {
+    // Only the first line, the output of a failed command is shown in the check run's text
+    let mut summary = record
+        .to_string()
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+
+    if !record.steps.is_empty() {
+        summary.push_str("\n\n| Step | Command | Duration |\n| --- | --- | --- |");
+        for step in &record.steps {
+            summary.push_str(&format!(
+                "\n| {} | `{}` | {:.1}s |",
+                step.step,
+                step.command.replace('|', "\\|"),
+                step.duration.as_secs_f64()
+            ));
+        }
+    }
+
+    if let Some(size) = record.artifact_size {
+        summary.push_str(&format!(
+            "\n\n**Artifact size:** {:.1} MB",
+            size as f64 / 1_000_000.0
+        ));
+    }
+
+    summary
+}
+
+fn log_tail(output: &[String]) -> String {
+    let tail = truncate_start(output.join("\n"), MAX_OUTPUT_LENGTH - 100);
+
+    format!("Last lines of output:\n\n```\n{tail}\n```")
+}
+
+/// Shortens the string to at most max_len bytes by cutting off its start
+fn truncate_start(s: String, max_len: usize) -> String {
+    if s.len() <= max_len {
+        return s;
+    }
+
+    let mut start = s.len() - max_len;
+    while !s.is_char_boundary(start) {
+        start += 1;
+    }
+
+    s[start..].to_string()
+}
+
+/// Turns a compiler error into an annotation, if it's about a file in the repo
+fn annotation(d: &Diagnostic, repo_dir: &Path, build_dir: &Path) -> Option<CheckRunAnnotation> {
+    let repo_dir = std::path::absolute(repo_dir).ok()?;
+    let build_dir = std::path::absolute(build_dir).ok()?;
+
+    // Relative paths are relative to the build directory, where the compiler runs
+    let mut path = PathBuf::new();
+    for component in build_dir.join(&d.path).components() {
+        match component {
+            Component::ParentDir => {
+                path.pop();
+            }
+            Component::CurDir => {}
+            component => path.push(component),
+        }
+    }
+
+    Some(CheckRunAnnotation {
+        path: path
+            .strip_prefix(&repo_dir)
+            .ok()?
+            .to_string_lossy()
+            .into_owned(),
+        start_line: d.line,
+        end_line: d.line,
+        start_column: d.column,
+        end_column: d.column,
+        annotation_level: "failure".to_string(),
+        message: d.message.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::build::{BuildError, ErrorKind, Stage, Step, StepTiming};
+
+    fn record() -> BuildRecord {
+        let mut record = BuildRecord::new("id".to_string(), "qt6", "master");
+        record.number = Some(7);
+        record.commit_sha = Some("0123456789abcdef".to_string());
+        record
+    }
+
+    #[test]
+    fn summarizes_builds() {
+        let mut record = record();
+        record.steps = vec![
+            StepTiming {
+                step: Step::Cmake,
+                command: "cmake -G Ninja ..".to_string(),
+                duration: Duration::from_millis(1500),
+            },
+            StepTiming {
+                step: Step::PreDmg,
+                command: "ls | wc -l".to_string(),
+                duration: Duration::from_millis(200),
+            },
+        ];
+        record.artifact_size = Some(123_456_789);
+        record.finish(Ok(()));
+
+        let summary = summary(&record);
+        let lines: Vec<&str> = summary.lines().collect();
+        assert!(lines[0].starts_with("Build #7 of qt6 (master @ 0123456) succeeded in"));
+        assert_eq!(
+            lines[2..],
+            [
+                "| Step | Command | Duration |",
+                "| --- | --- | --- |",
+                "| cmake | `cmake -G Ninja ..` | 1.5s |",
+                "| pre_dmg | `ls \\| wc -l` | 0.2s |",
+                "",
+                "**Artifact size:** 123.5 MB",
+            ]
+        );
+    }
+
+    #[test]
+    fn summarizes_failed_builds_in_one_line() {
+        let mut record = record();
+        record.finish(Err(&BuildError {
+            stage: Stage::Build,
+            step: Some(Step::Compile),
+            command: Some("cmake --build .".to_string()),
+            kind: ErrorKind::Exit(2),
+            duration: Duration::from_secs(3),
+            output: vec!["error: expected ';'".to_string()],
+            diagnostics: vec![],
+        }));
+
+        let summary = summary(&record);
+        assert!(!summary.contains('\n'));
+        assert!(summary.ends_with(
+            "s: build stage failed in step compile running `cmake --build .`: exited with status 2 after 3.0s"
+        ));
+    }
+
+    #[test]
+    fn shows_the_end_of_the_log() {
+        assert_eq!(
+            log_tail(&["first".to_string(), "second".to_string()]),
+            "Last lines of output:\n\n```\nfirst\nsecond\n```"
+        );
+
+        let output = vec!["x".repeat(MAX_OUTPUT_LENGTH); 2];
+        assert!(log_tail(&output).len() <= MAX_OUTPUT_LENGTH);
+    }
+
+    #[test]
+    fn truncates_at_char_boundaries() {
+        assert_eq!(truncate_start("abc".to_string(), 3), "abc");
+        assert_eq!(truncate_start("abcdef".to_string(), 2), "ef");
+        assert_eq!(truncate_start(String::new(), 0), "");
+
+        // ö is 2 bytes long, a cut in its middle drops all of it
+        assert_eq!(truncate_start("aöb".to_string(), 2), "b");
+        assert_eq!(truncate_start("aöb".to_string(), 3), "öb");
+        // 🦀 is 4 bytes long
+        assert_eq!(truncate_start("🦀🦀".to_string(), 7), "🦀");
+        assert_eq!(truncate_start("🦀🦀".to_string(), 3), "");
+    }
+
+    #[test]
+    fn annotates_files_of_the_repo() {
+        let repo_dir = Path::new("/src/chatterino");
+        let build_dir = repo_dir.join("build");
+        let diagnostic = |path: &str| Diagnostic {
+            path: path.to_string(),
+            line: 12,
+            column: Some(5),
+            message: "expected ';'".to_string(),
+        };
+
+        let relative = annotation(&diagnostic("../src/main.cpp"), repo_dir, &build_dir).unwrap();
+        assert_eq!(relative.path, "src/main.cpp");
+        assert_eq!((relative.start_line, relative.end_line), (12, 12));
+        assert_eq!(
+            (relative.start_column, relative.end_column),
+            (Some(5), Some(5))
+        );
+        assert_eq!(relative.annotation_level, "failure");
+        assert_eq!(relative.message, "expected ';'");
+
+        let absolute = annotation(
+            &diagnostic("/src/chatterino/./lib/x.hpp"),
+            repo_dir,
+            &build_dir,
+        );
+        assert_eq!(absolute.map(|a| a.path), Some("lib/x.hpp".to_string()));
+
+        // Files outside of the repo, e.g. system headers, can't be annotated
+        assert!(annotation(&diagnostic("/usr/include/stdio.h"), repo_dir, &build_dir).is_none());
+        assert!(annotation(&diagnostic("../../other/main.cpp"), repo_dir, &build_dir).is_none());
+    }
+}
@@ -0,0 +1,84 @@
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+// Matches errors as clang & gcc print them, e.g.
+// /path/to/src/main.cpp:12:5: error: use of undeclared identifier 'foo'
+static COMPILER_ERROR: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"^(?P<path>[^\s:][^:]*):(?P<line>\d+):(?:(?P<column>\d+):)?\s*(?:fatal )?error: (?P<message>.+)$",
+    )
+    .unwrap()
+});
+
+/// An error reported by the compiler for a line of a source file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    // The path as the compiler printed it, either absolute or relative to the build directory
+    pub path: String,
+
+    pub line: u32,
+    pub column: Option<u32>,
+
+    pub message: String,
+}
+
+/// Parses a line of compiler output, returning the error it reports if there is one
+pub fn parse(line: &str) -> Option<Diagnostic> {
+    let captures = COMPILER_ERROR.captures(line.trim_end())?;
+
+    Some(Diagnostic {
+        path: captures["path"].to_string(),
+        line: captures["line"].parse().ok()?,
+        column: captures
+            .name("column")
+            .and_then(|column| column.as_str().parse().ok()),
+        message: captures["message"].to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_compiler_errors() {
+        assert_eq!(
+            parse("/src/chatterino/src/main.cpp:12:5: error: use of undeclared identifier 'foo'"),
+            Some(Diagnostic {
+                path: "/src/chatterino/src/main.cpp".to_string(),
+                line: 12,
+                column: Some(5),
+                message: "use of undeclared identifier 'foo'".to_string(),
+            })
+        );
+        assert_eq!(
+            parse("../src/common/Args.hpp:3:10: fatal error: 'QString' file not found"),
+            Some(Diagnostic {
+                path: "../src/common/Args.hpp".to_string(),
+                line: 3,
+                column: Some(10),
+                message: "'QString' file not found".to_string(),
+            })
+        );
+        assert_eq!(
+            parse("src/widgets/Window.cpp:80: error: expected ';'"),
+            Some(Diagnostic {
+                path: "src/widgets/Window.cpp".to_string(),
+                line: 80,
+                column: None,
+                message: "expected ';'".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn ignores_other_output() {
+        assert_eq!(
+            parse("/src/main.cpp:12:5: warning: unused variable 'x' [-Wunused-variable]"),
+            None
+        );
+        assert_eq!(parse("[12/340] Building CXX object src/main.cpp.o"), None);
+        assert_eq!(parse("ninja: build stopped: subcommand failed."), None);
+    }
+}
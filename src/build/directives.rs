@@ -0,0 +1,101 @@
+use std::sync::{Arc, LazyLock};
+
+use regex::Regex;
+
+use super::Pipeline;
+
+// e.g. [skip artifacts], [skip macos] or [build: Chatterino-Qt-6.5.0.dmg, qt5]
+static DIRECTIVE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)\[\s*(?:(?P<skip>skip\s+(?:artifacts|macos))|build\s*:(?P<build>[^\]]*))\s*\]")
+        .unwrap()
+});
+
+/// Directives in a commit message that control which pipelines build the commit
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Directives {
+    // [skip artifacts] or [skip macos]: no pipeline builds the commit
+    pub skip: bool,
+
+    // [build: name, ...]: only the pipelines with one of these names or asset names build the
+    // commit, even if their path filters would skip it
+    pub build: Vec<String>,
+}
+
+impl Directives {
+    pub fn parse(message: &str) -> Self {
+        let mut directives = Self::default();
+
+        for captures in DIRECTIVE.captures_iter(message) {
+            if captures.name("skip").is_some() {
+                directives.skip = true;
+            }
+
+            if let Some(build) = captures.name("build") {
+                directives.build.extend(
+                    build
+                        .as_str()
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|name| !name.is_empty())
+                        .map(String::from),
+                );
+            }
+        }
+
+        directives
+    }
+
+    /// Returns the pipelines that build the commit
+    pub fn filter(&self, pipelines: Vec<Arc<Pipeline>>) -> Vec<Arc<Pipeline>> {
+        if self.skip {
+            return vec![];
+        }
+
+        if self.build.is_empty() {
+            return pipelines;
+        }
+
+        pipelines
+            .into_iter()
+            .filter(|p| {
+                self.build
+                    .iter()
+                    .any(|name| name == p.name() || name == p.asset_name())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_directives() {
+        assert_eq!(
+            Directives::parse("Update README [skip artifacts]"),
+            Directives {
+                skip: true,
+                build: vec![],
+            }
+        );
+        assert!(Directives::parse("[Skip macOS] fix typo").skip);
+        assert_eq!(
+            Directives::parse(
+                "Fix crash on startup\n\n[build: Chatterino-Qt-6.5.0.dmg, qt5] [build:qt6]"
+            ),
+            Directives {
+                skip: false,
+                build: vec![
+                    "Chatterino-Qt-6.5.0.dmg".to_string(),
+                    "qt5".to_string(),
+                    "qt6".to_string()
+                ],
+            }
+        );
+        assert_eq!(
+            Directives::parse("Skip artifacts when building [skip ci]"),
+            Directives::default()
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use std::{fmt, time::Duration};
+
+use super::{diagnostics::Diagnostic, Step};
+
+/// The stages of a build, in the order they run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    // Cloning or updating the repo
+    Checkout,
+    // Running the steps that build the artifact
+    Build,
+    // Uploading the artifact to the GitHub release
+    Upload,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stage::Checkout => "checkout",
+            Stage::Build => "build",
+            Stage::Upload => "upload",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    // The command exited with a non-zero exit code
+    Exit(i32),
+    // The command was killed by a signal
+    Signal(i32),
+    // Something other than a command failed, e.g. the command could not be started
+    Other(String),
+}
+
+/// Why running a single command failed
+#[derive(Debug, Clone)]
+pub struct CommandError {
+    pub kind: ErrorKind,
+
+    // The last lines the command wrote to stdout or stderr
+    pub output: Vec<String>,
+
+    // Compiler errors found in the command's output
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl CommandError {
+    pub fn other(e: impl fmt::Display) -> Self {
+        Self {
+            kind: ErrorKind::Other(e.to_string()),
+            output: vec![],
+            diagnostics: vec![],
+        }
+    }
+}
+
+/// Why a build failed
+#[derive(Debug, Clone)]
+pub struct BuildError {
+    pub stage: Stage,
+
+    // The step that failed, if the failure happened while running a step
+    pub step: Option<Step>,
+
+    // The command that failed, if the failure happened while running a command
+    pub command: Option<String>,
+
+    pub kind: ErrorKind,
+
+    // How long the failing step or stage ran for before it failed
+    pub duration: Duration,
+
+    // The last lines of output of the failing command
+    pub output: Vec<String>,
+
+    // Compiler errors found in the output of the failing command
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl BuildError {
+    /// Creates an error for a failure that didn't happen in a command
+    pub fn other(stage: Stage, duration: Duration, e: &anyhow::Error) -> Self {
+        Self {
+            stage,
+            step: None,
+            command: None,
+            kind: ErrorKind::Other(format!("{e:#}")),
+            duration,
+            output: vec![],
+            diagnostics: vec![],
+        }
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} stage failed", self.stage)?;
+
+        if let Some(step) = self.step {
+            write!(f, " in step {step}")?;
+        }
+
+        if let Some(command) = &self.command {
+            write!(f, " running `{command}`")?;
+        }
+
+        match &self.kind {
+            ErrorKind::Exit(code) => write!(f, ": exited with status {code}")?,
+            ErrorKind::Signal(signal) => write!(f, ": killed by signal {signal}")?,
+            ErrorKind::Other(message) => write!(f, ": {message}")?,
+        }
+
+        write!(f, " after {:.1}s", self.duration.as_secs_f64())?;
+
+        if !self.output.is_empty() {
+            write!(f, "\nLast {} lines of output:", self.output.len())?;
+            for line in &self.output {
+                write!(f, "\n{line}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for BuildError {}
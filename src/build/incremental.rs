@@ -0,0 +1,147 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::now;
+
+#[allow(unused)]
+use tracing::log::*;
+
+// Name of the file inside the build directory that remembers how the build directory was configured
+const STATE_FILE_NAME: &str = ".artifact-builder-state.json";
+
+// Commands whose output identifies the toolchain. If any of their output changes, the build
+// directory is considered stale and a clean build is done.
+const TOOLCHAIN_COMMANDS: &[&[&str]] = &[&["cmake", "--version"], &["c++", "--version"]];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BuildDirState {
+    // Hash of the cmake command & toolchain versions the build directory was configured with
+    pub fingerprint: String,
+
+    // Unix timestamp (in seconds) of the last clean build in this build directory
+    pub last_clean_build: u64,
+}
+
+impl BuildDirState {
+    fn path(build_dir: &Path) -> PathBuf {
+        build_dir.join(STATE_FILE_NAME)
+    }
+
+    pub fn load(build_dir: &Path) -> Option<Self> {
+        let data = std::fs::read(Self::path(build_dir)).ok()?;
+
+        match serde_json::from_slice(&data) {
+            Ok(state) => Some(state),
+            Err(e) => {
+                warn!("Ignoring unreadable build directory state: {e}");
+                None
+            }
+        }
+    }
+
+    pub fn save(&self, build_dir: &Path) -> anyhow::Result<()> {
+        std::fs::write(Self::path(build_dir), serde_json::to_vec(self)?)?;
+
+        Ok(())
+    }
+}
+
+/// Returns a fingerprint of the given cmake command and the installed toolchain
+pub async fn fingerprint(cmake_command: &[String]) -> String {
+    let mut hasher = Sha256::new();
+
+    for arg in cmake_command {
+        hasher.update(arg.as_bytes());
+        hasher.update([0]);
+    }
+
+    for command in TOOLCHAIN_COMMANDS {
+        hasher.update([0]);
+        match tokio::process::Command::new(command[0])
+            .args(&command[1..])
+            .output()
+            .await
+        {
+            Ok(output) => hasher.update(&output.stdout),
+            Err(e) => debug!("Unable to run {:?} for toolchain fingerprint: {e}", command),
+        }
+    }
+
+    hex::encode(hasher.finalize())
+}
+
+/// Returns the reason a clean build is required, or None if the build directory can be reused
+pub fn clean_build_reason(
+    state: Option<&BuildDirState>,
+    fingerprint: &str,
+    clean_build_interval: Option<Duration>,
+) -> Option<&'static str> {
+    let Some(state) = state else {
+        return Some("no previous build");
+    };
+
+    if state.fingerprint != fingerprint {
+        return Some("cmake args or toolchain changed");
+    }
+
+    if let Some(interval) = clean_build_interval {
+        if now().saturating_sub(state.last_clean_build) >= interval.as_secs() {
+            return Some("scheduled clean build");
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(fingerprint: &str, last_clean_build: u64) -> BuildDirState {
+        BuildDirState {
+            fingerprint: fingerprint.to_string(),
+            last_clean_build,
+        }
+    }
+
+    #[test]
+    fn requires_clean_builds() {
+        let day = Duration::from_secs(24 * 60 * 60);
+
+        assert_eq!(
+            clean_build_reason(None, "abc", None),
+            Some("no previous build")
+        );
+        assert_eq!(
+            clean_build_reason(Some(&state("abc", now())), "def", None),
+            Some("cmake args or toolchain changed")
+        );
+        assert_eq!(
+            clean_build_reason(
+                Some(&state("abc", now() - 2 * day.as_secs())),
+                "abc",
+                Some(day)
+            ),
+            Some("scheduled clean build")
+        );
+    }
+
+    #[test]
+    fn reuses_build_directories() {
+        let day = Duration::from_secs(24 * 60 * 60);
+
+        assert_eq!(
+            clean_build_reason(Some(&state("abc", 0)), "abc", None),
+            None
+        );
+        assert_eq!(
+            clean_build_reason(Some(&state("abc", now() - 60)), "abc", Some(day)),
+            None
+        );
+    }
+}
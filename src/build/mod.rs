@@ -1,36 +1,366 @@
-use anyhow::anyhow;
+use serde::Deserialize;
 
 use std::{
-    collections::HashMap,
-    ffi::OsStr,
+    collections::{HashMap, VecDeque},
+    fmt,
+    os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
     process::{ExitStatus, Stdio},
-    sync::Arc,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
 };
 use tokio::{
     io::{AsyncBufReadExt, BufReader},
     process::Command as TokioCommand,
+    task::AbortHandle,
 };
 use tokio_stream::StreamExt;
+use tracing::Instrument;
 
 use tracing::log::*;
 
+use crate::config::Command;
+use crate::state::JsonStore;
+
+mod branch_rule;
+mod checks;
+pub mod diagnostics;
+mod directives;
+mod error;
+mod incremental;
+mod paths;
 pub mod pipeline;
+mod pull_request;
+mod record;
+mod repository;
 
-pub use pipeline::Pipeline;
+pub use directives::Directives;
+pub use error::{BuildError, CommandError, ErrorKind, Stage};
+pub use paths::PathFilter;
+pub use pipeline::{Destination, Pipeline};
+pub use pull_request::PullRequests;
+pub use record::{BuildRecord, BuildStatus, StepTiming};
+pub use repository::{Repositories, Repository};
 pub type Pipelines = HashMap<String, Vec<Arc<Pipeline>>>;
 
-#[tracing::instrument(skip())]
-async fn run_command<Cmd>(
-    command: Cmd,
+// Number of output lines of a failed command that are kept for its error
+const OUTPUT_TAIL_LINES: usize = 50;
+
+// Number of compiler errors of a failed command that are kept for its error
+const MAX_DIAGNOSTICS: usize = 50;
+
+// Number of webhook delivery IDs that are remembered to detect redeliveries
+const MAX_DELIVERIES: usize = 1000;
+
+/// State of all pipelines that must survive restarts of the builder
+pub struct State {
+    // Last used build number of each pipeline
+    pub build_numbers: JsonStore<HashMap<String, u64>>,
+
+    // Commit hash of each pipeline's artifact that was built, but failed to upload
+    pub pending_uploads: JsonStore<HashMap<String, String>>,
+
+    // IDs of the most recently received webhook deliveries, oldest first
+    pub deliveries: JsonStore<VecDeque<String>>,
+
+    // Last seen commit hash of each polled branch, by owner/name/branch
+    pub branch_heads: JsonStore<HashMap<String, String>>,
+
+    // Commit hash of each pipeline's last uploaded artifact
+    pub built_commits: JsonStore<HashMap<String, String>>,
+}
+
+impl State {
+    pub fn open(state_dir: &Path) -> anyhow::Result<Self> {
+        Ok(Self {
+            build_numbers: JsonStore::open(&state_dir.join("build-numbers.json"))?,
+            pending_uploads: JsonStore::open(&state_dir.join("pending-uploads.json"))?,
+            deliveries: JsonStore::open(&state_dir.join("deliveries.json"))?,
+            branch_heads: JsonStore::open(&state_dir.join("branch-heads.json"))?,
+            built_commits: JsonStore::open(&state_dir.join("built-commits.json"))?,
+        })
+    }
+
+    /// Returns the key of the branch in the saved branch heads
+    pub fn branch_head_key(full_name: &str, branch: &str) -> String {
+        format!("{full_name}/{branch}")
+    }
+
+    /// Saves the head of a branch of a polled repository once it's built, by a poll or a webhook,
+    /// so the next poll doesn't build it again
+    pub fn set_branch_head(&self, full_name: &str, branch: &str, commit: &str) {
+        if let Err(e) = self.branch_heads.update(|heads| {
+            heads.insert(Self::branch_head_key(full_name, branch), commit.to_string())
+        }) {
+            warn!("Failed saving the head of {branch}: {e:#}");
+        }
+    }
+
+    /// Removes the build numbers, pending uploads & built commits of the pipelines whose keys start
+    /// with the prefix, e.g. of a closed pull request
+    pub fn forget_pipelines(&self, prefix: &str) -> anyhow::Result<()> {
+        self.build_numbers
+            .update(|numbers| numbers.retain(|key, _| !key.starts_with(prefix)))?;
+        self.pending_uploads
+            .update(|pending_uploads| pending_uploads.retain(|key, _| !key.starts_with(prefix)))?;
+        self.built_commits
+            .update(|built_commits| built_commits.retain(|key, _| !key.starts_with(prefix)))?;
+
+        Ok(())
+    }
+
+    /// Prefixes the keys of pipelines with the repository's full name, for state that was kept
+    /// before multiple repositories were supported. Nothing is changed if any key has the prefix
+    /// already, since the keys were migrated or written with the prefix then
+    pub fn prefix_legacy_keys(&self, full_name: &str) -> anyhow::Result<()> {
+        let prefix = format!("{full_name}/");
+
+        let migrated = self
+            .build_numbers
+            .read(|numbers| numbers.keys().any(|key| key.starts_with(&prefix)))
+            || self
+                .pending_uploads
+                .read(|pending_uploads| pending_uploads.keys().any(|key| key.starts_with(&prefix)));
+        if migrated {
+            return Ok(());
+        }
+
+        fn prefix_keys<V>(map: &mut HashMap<String, V>, prefix: &str) {
+            let legacy_keys: Vec<String> = map.keys().cloned().collect();
+
+            for key in legacy_keys {
+                if let Some(value) = map.remove(&key) {
+                    map.insert(format!("{prefix}{key}"), value);
+                }
+            }
+        }
+
+        self.build_numbers
+            .update(|numbers| prefix_keys(numbers, &prefix))?;
+        self.pending_uploads
+            .update(|pending_uploads| prefix_keys(pending_uploads, &prefix))?;
+
+        Ok(())
+    }
+
+    /// Remembers the webhook delivery, returning false if it was received before
+    pub fn record_delivery(&self, delivery_id: &str) -> anyhow::Result<bool> {
+        self.deliveries.update(|deliveries| {
+            if deliveries.iter().any(|d| d == delivery_id) {
+                return false;
+            }
+
+            if deliveries.len() == MAX_DELIVERIES {
+                deliveries.pop_front();
+            }
+            deliveries.push_back(delivery_id.to_string());
+
+            true
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BuildRequest {
+    // Wipe the build directory before building, even if the pipeline builds incrementally
+    pub clean: bool,
+
+    // The commit that triggered this build, if known
+    pub commit: Option<String>,
+
+    // The commit the branch pointed to before the push that triggered this build, if known.
+    // The commits in between are listed in the release notes
+    pub before: Option<String>,
+
+    // ID of the webhook delivery that triggered this build
+    pub delivery_id: Option<String>,
+
+    // The files changed between `before` and `commit`, if the push listed all of them.
+    // Otherwise they're looked up in the clone for pipelines with path filters
+    pub changed_files: Option<Vec<String>>,
+
+    // Build even if the path filters would skip the commit, e.g. as requested by the commit message
+    pub force: bool,
+}
+
+/// Information about a single run of a pipeline
+#[derive(Debug, Clone)]
+pub struct BuildContext {
+    // Unique ID of this build
+    pub id: String,
+
+    // Sequential number of this build within its pipeline
+    pub number: u64,
+
+    // The commit hash that is being built
+    pub commit_sha: String,
+}
+
+impl BuildContext {
+    pub fn short_sha(&self) -> &str {
+        &self.commit_sha[..self.commit_sha.len().min(7)]
+    }
+}
+
+/// The steps of a pipeline, in the order they run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    // pre_cmake_commands
+    PreCmake,
+    // Configuring the project with cmake
+    Cmake,
+    // Compiling the project
+    Compile,
+    // pre_package_commands
+    PrePackage,
+    // MacDeploy.sh
+    Package,
+    // pre_dmg_commands
+    PreDmg,
+    // CreateDMG.sh
+    Dmg,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Step::PreCmake => "pre_cmake",
+            Step::Cmake => "cmake",
+            Step::Compile => "compile",
+            Step::PrePackage => "pre_package",
+            Step::Package => "package",
+            Step::PreDmg => "pre_dmg",
+            Step::Dmg => "dmg",
+        })
+    }
+}
+
+/// Returns the current time as a unix timestamp in seconds
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Keeps track of the running build job for each clone directory.
+/// Pipelines sharing a clone directory can't build at the same time, so starting a new job
+/// aborts the job that is currently running in that directory.
+#[derive(Default)]
+pub struct Jobs {
+    current: Mutex<HashMap<PathBuf, AbortHandle>>,
+}
+
+impl Jobs {
+    /// Spawns a job building the given pipelines one after the other.
+    /// All pipelines must share the same clone directory.
+    pub fn spawn(&self, pipelines: Vec<Arc<Pipeline>>, request: BuildRequest) {
+        self.spawn_all(vec![(pipelines, request)]);
+    }
+
+    /// Spawns a job building each group of pipelines with its request, one after the other.
+    /// All pipelines must share the same clone directory.
+    pub fn spawn_all(&self, builds: Vec<(Vec<Arc<Pipeline>>, BuildRequest)>) {
+        self.spawn_all_then(builds, |_| ());
+    }
+
+    /// Like spawn_all, calling `on_built` with the index of each group of pipelines once all of
+    /// them finished building, whether they succeeded or not. Groups that didn't finish because
+    /// the job was aborted aren't passed to it
+    pub fn spawn_all_then(
+        &self,
+        builds: Vec<(Vec<Arc<Pipeline>>, BuildRequest)>,
+        on_built: impl Fn(usize) + Send + 'static,
+    ) {
+        let Some(repo_dir) = builds
+            .iter()
+            .flat_map(|(pipelines, _)| pipelines)
+            .next()
+            .map(|p| p.repo_dir().to_path_buf())
+        else {
+            return;
+        };
+
+        let mut current = self.current.lock().unwrap();
+
+        if let Some(old_abort_handle) = current.remove(&repo_dir) {
+            info!("Aborting old job");
+            old_abort_handle.abort();
+        }
+
+        let span = tracing::info_span!(
+            "job",
+            delivery_id = builds
+                .first()
+                .and_then(|(_, request)| request.delivery_id.as_deref())
+                .unwrap_or("none")
+        );
+
+        let handle = tokio::spawn(
+            async move {
+                for (i, (pipelines, request)) in builds.into_iter().enumerate() {
+                    for p in pipelines {
+                        let res = p.build(&request).await;
+
+                        if let Some(record) = p.last_build() {
+                            match res {
+                                Ok(()) => info!("{record}"),
+                                Err(_) => error!("{record}"),
+                            }
+                        }
+                    }
+
+                    on_built(i);
+                }
+            }
+            .instrument(span),
+        );
+
+        current.insert(repo_dir, handle.abort_handle());
+    }
+
+    /// Aborts the job running in the clone directory, if there is one
+    pub fn abort(&self, repo_dir: &Path) {
+        if let Some(abort_handle) = self.current.lock().unwrap().remove(repo_dir) {
+            info!("Aborting job in {repo_dir:?}");
+            abort_handle.abort();
+        }
+    }
+
+    /// Returns whether a job is still running in the clone directory
+    pub fn is_running(&self, repo_dir: &Path) -> bool {
+        self.current
+            .lock()
+            .unwrap()
+            .get(repo_dir)
+            .is_some_and(|handle| !handle.is_finished())
+    }
+}
+
+#[tracing::instrument(skip(envs))]
+async fn run_command(
+    command: &Command,
+    cwd: &Path,
     envs: Option<&HashMap<String, String>>,
-) -> anyhow::Result<()>
-where
-    Cmd: AsRef<OsStr> + std::fmt::Debug,
-{
-    let mut cmd = TokioCommand::new("sh");
+) -> Result<(), CommandError> {
+    let mut cmd = match command {
+        Command::Shell(command) => {
+            let mut cmd = TokioCommand::new("sh");
+            cmd.arg("-c");
+            cmd.arg(command);
+            cmd
+        }
+        Command::Argv(argv) => {
+            let (program, args) = argv
+                .split_first()
+                .ok_or_else(|| CommandError::other("Command has an empty argv"))?;
+            let mut cmd = TokioCommand::new(program);
+            cmd.args(args);
+            cmd
+        }
+    };
 
-    cmd.arg("-c");
-    cmd.arg(command);
+    cmd.current_dir(cwd);
 
     cmd.stdout(Stdio::piped());
     cmd.stderr(Stdio::piped());
@@ -39,7 +369,7 @@ where
         cmd.envs(envs);
     }
 
-    let mut child = cmd.spawn()?;
+    let mut child = cmd.spawn().map_err(CommandError::other)?;
 
     let stdout = child.stdout.take().unwrap();
     let stdout_reader = BufReader::new(stdout).lines();
@@ -52,13 +382,29 @@ where
     let handle: tokio::task::JoinHandle<Result<ExitStatus, std::io::Error>> =
         tokio::spawn(async move { child.wait().await });
 
+    let mut output: VecDeque<String> = VecDeque::with_capacity(OUTPUT_TAIL_LINES);
+    let mut diagnostics = vec![];
+    let mut push_output = |line: String| {
+        // Errors are collected from all of the output, they're likely scrolled out of the tail
+        if diagnostics.len() < MAX_DIAGNOSTICS {
+            diagnostics.extend(diagnostics::parse(&line));
+        }
+
+        if output.len() == OUTPUT_TAIL_LINES {
+            output.pop_front();
+        }
+        output.push_back(line);
+    };
+
     loop {
         tokio::select! {
             Some(Ok(line)) = stdout_reader_stream.next() => {
                 info!("stdout: {line:?}");
+                push_output(line);
             }
             Some(Ok(line)) = stderr_reader_stream.next() => {
                 info!("stderr: {line:?}");
+                push_output(line);
             }
             else => {
                 break;
@@ -66,15 +412,176 @@ where
         }
     }
 
-    let status = handle.await??;
+    let status = handle
+        .await
+        .map_err(CommandError::other)?
+        .map_err(CommandError::other)?;
+
+    let kind = match (status.code(), status.signal()) {
+        (Some(0), _) => return Ok(()),
+        (Some(code), _) => ErrorKind::Exit(code),
+        (None, Some(signal)) => ErrorKind::Signal(signal),
+        (None, None) => ErrorKind::Other("Process exited without a status code".to_string()),
+    };
+
+    Err(CommandError {
+        kind,
+        output: output.into(),
+        diagnostics,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes_legacy_keys_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = State::open(dir.path()).unwrap();
+        state
+            .build_numbers
+            .update(|numbers| {
+                numbers.insert("master/qt6".to_string(), 41);
+                numbers.insert("release/2.4/qt6".to_string(), 3);
+            })
+            .unwrap();
+        state
+            .pending_uploads
+            .update(|pending_uploads| {
+                pending_uploads.insert("master/qt6".to_string(), "abc123".to_string());
+            })
+            .unwrap();
+
+        state.prefix_legacy_keys("owner/repo").unwrap();
+        // e.g. after a restart
+        state.prefix_legacy_keys("owner/repo").unwrap();
+
+        let mut numbers: Vec<_> = state
+            .build_numbers
+            .read(|numbers| numbers.clone())
+            .into_iter()
+            .collect();
+        numbers.sort();
+        assert_eq!(
+            numbers,
+            vec![
+                ("owner/repo/master/qt6".to_string(), 41),
+                ("owner/repo/release/2.4/qt6".to_string(), 3),
+            ]
+        );
+        assert_eq!(
+            state
+                .pending_uploads
+                .read(|pending_uploads| pending_uploads.get("owner/repo/master/qt6").cloned()),
+            Some("abc123".to_string())
+        );
+    }
+
+    /// Runs the command, returning how it failed & the error of a build step running it
+    async fn failure(command: &str) -> (CommandError, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let command = Command::Shell(command.to_string());
+        let e = run_command(&command, dir.path(), None).await.unwrap_err();
+
+        let build_error = BuildError {
+            stage: Stage::Build,
+            step: Some(Step::Compile),
+            command: Some(command.to_string()),
+            kind: e.kind.clone(),
+            duration: std::time::Duration::from_millis(1500),
+            output: e.output.clone(),
+            diagnostics: e.diagnostics.clone(),
+        };
+
+        (e, build_error.to_string())
+    }
+
+    #[tokio::test]
+    async fn runs_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let envs = HashMap::from([("STEP".to_string(), "compile".to_string())]);
+        std::fs::write(dir.path().join("CMakeLists.txt"), "").unwrap();
+
+        run_command(&Command::Argv(vec!["true".to_string()]), dir.path(), None)
+            .await
+            .unwrap();
+        run_command(
+            &Command::Shell(r#"test "$STEP" = compile && test -f CMakeLists.txt"#.to_string()),
+            dir.path(),
+            Some(&envs),
+        )
+        .await
+        .unwrap();
+
+        let e = run_command(&Command::Argv(vec![]), dir.path(), None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            e.kind,
+            ErrorKind::Other("Command has an empty argv".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_exit_codes() {
+        let (e, rendered) = failure("false").await;
+        assert_eq!(e.kind, ErrorKind::Exit(1));
+        assert!(e.output.is_empty());
+        assert_eq!(
+            rendered,
+            "build stage failed in step compile running `false`: exited with status 1 after 1.5s"
+        );
+
+        let (e, rendered) =
+            failure("echo 'src/main.cpp:3:14: error: expected ;'; echo done; exit 3").await;
+        assert_eq!(e.kind, ErrorKind::Exit(3));
+        assert_eq!(e.output, ["src/main.cpp:3:14: error: expected ;", "done"]);
+        assert_eq!(e.diagnostics.len(), 1);
+        assert_eq!(
+            rendered,
+            "build stage failed in step compile running `echo 'src/main.cpp:3:14: error: expected ;'; echo done; exit 3`: exited with status 3 after 1.5s\n\
+             Last 2 lines of output:\n\
+             src/main.cpp:3:14: error: expected ;\n\
+             done"
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_signals() {
+        let (e, rendered) = failure("kill -9 $$").await;
+        assert_eq!(e.kind, ErrorKind::Signal(9));
+        assert_eq!(
+            rendered,
+            "build stage failed in step compile running `kill -9 $$`: killed by signal 9 after 1.5s"
+        );
+    }
+
+    #[test]
+    fn records_deliveries() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = State::open(dir.path()).unwrap();
 
-    if let Some(code) = status.code() {
-        if code == 0 {
-            Ok(())
-        } else {
-            Err(anyhow!("Process exited with status {code}"))
+        assert!(state.record_delivery("first").unwrap());
+        assert!(!state.record_delivery("first").unwrap());
+
+        for i in 1..MAX_DELIVERIES {
+            assert!(state.record_delivery(&i.to_string()).unwrap());
         }
-    } else {
-        Err(anyhow!("Process exited without a status code?"))
+        assert!(!state.record_delivery("first").unwrap());
+
+        // The oldest delivery is forgotten to make room for a new one
+        assert!(state.record_delivery("new").unwrap());
+        assert_eq!(state.deliveries.read(|d| d.len()), MAX_DELIVERIES);
+        assert_eq!(
+            state.deliveries.read(|d| d.front().cloned()),
+            Some("1".to_string())
+        );
+
+        // The deliveries survive restarts
+        let state = State::open(dir.path()).unwrap();
+        assert!(!state.record_delivery("new").unwrap());
+        assert!(!state.record_delivery("1").unwrap());
+        assert!(state.record_delivery("first").unwrap());
     }
 }
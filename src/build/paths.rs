@@ -0,0 +1,106 @@
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+/// Decides whether a push is built from the files it changed
+pub struct PathFilter {
+    // None if every file is included
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl PathFilter {
+    pub fn new(include: Option<&[String]>, exclude: Option<&[String]>) -> anyhow::Result<Self> {
+        Ok(Self {
+            include: include.map(glob_set).transpose()?,
+            exclude: glob_set(exclude.unwrap_or_default())?,
+        })
+    }
+
+    /// Returns why a push changing the given files isn't built, or None if at least one of the
+    /// files is included & not excluded
+    pub fn skip_reason(&self, changed_files: &[String]) -> Option<String> {
+        let matches = |file: &String| {
+            self.include
+                .as_ref()
+                .is_none_or(|include| include.is_match(file))
+                && !self.exclude.is_match(file)
+        };
+
+        if changed_files.iter().any(matches) {
+            return None;
+        }
+
+        Some(match changed_files {
+            [] => "No files changed".to_string(),
+            [file] => format!("The changed file {file} doesn't match the path filters"),
+            files => format!(
+                "None of the {} changed files match the path filters",
+                files.len()
+            ),
+        })
+    }
+}
+
+// In globs, * doesn't match a /, ** does
+fn glob_set(globs: &[String]) -> anyhow::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+
+    for glob in globs {
+        builder.add(
+            GlobBuilder::new(glob)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| anyhow::anyhow!("Invalid path glob {glob}: {e}"))?,
+        );
+    }
+
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files(files: &[&str]) -> Vec<String> {
+        files.iter().map(|f| f.to_string()).collect()
+    }
+
+    #[test]
+    fn skips_excluded_files() {
+        let filter =
+            PathFilter::new(None, Some(&files(&["**/*.md", ".github/**", "docs/**"]))).unwrap();
+
+        assert!(filter
+            .skip_reason(&files(&["README.md", ".github/workflows/build.yml"]))
+            .is_some());
+        assert!(filter.skip_reason(&files(&["docs/src/a.md"])).is_some());
+        assert_eq!(
+            filter.skip_reason(&files(&["README.md", "src/main.cpp"])),
+            None
+        );
+    }
+
+    #[test]
+    fn only_builds_included_files() {
+        let filter = PathFilter::new(
+            Some(&files(&["src/**", "CMakeLists.txt"])),
+            Some(&files(&["src/**/*.md"])),
+        )
+        .unwrap();
+
+        assert_eq!(filter.skip_reason(&files(&["CMakeLists.txt"])), None);
+        assert_eq!(
+            filter.skip_reason(&files(&["src/widgets/Window.cpp"])),
+            None
+        );
+        assert_eq!(
+            filter.skip_reason(&files(&["src/README.md"])),
+            Some("The changed file src/README.md doesn't match the path filters".to_string())
+        );
+        // * doesn't match a /
+        assert!(PathFilter::new(Some(&files(&["*.cpp"])), None)
+            .unwrap()
+            .skip_reason(&files(&["src/main.cpp"]))
+            .is_some());
+        assert!(filter.skip_reason(&[]).is_some());
+    }
+}
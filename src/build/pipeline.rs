@@ -1,12 +1,57 @@
 use anyhow::Context;
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 use tracing::log::*;
 
-use super::run_command;
-use crate::github::{self, model::UploadReleaseAssetRoot};
+use super::{
+    checks::CheckRun,
+    incremental::{self, BuildDirState},
+    now, pull_request, run_command, BuildContext, BuildError, BuildRecord, BuildRequest,
+    PathFilter, Stage, State, Step, StepTiming,
+};
+use crate::config::{Command, EnvironmentVariable, ReleaseRef};
+use crate::github::{
+    model::{GetReleaseRoot, ReleaseAsset},
+    GithubClient,
+};
+use crate::template;
+
+const DEFAULT_RELEASE_NAME: &str = "{tag} ({short_sha}, {date})";
+const DEFAULT_RELEASE_BODY: &str = "Built from {commit} on {branch} at {date}\n\n{changelog}";
+
+// Commits beyond this are left out of the changelog
+const CHANGELOG_MAX_COMMITS: usize = 100;
+
+/// Where a pipeline puts the artifacts it builds
+#[derive(Debug, Clone)]
+pub enum Destination {
+    // Uploaded as an asset of a GitHub release
+    Release(ReleaseRef),
+
+    // Copied into a directory the builder serves the artifacts from
+    LocalStore {
+        dir: PathBuf,
+
+        // URL the artifacts in the directory are served at, without a trailing slash
+        url: String,
+    },
+}
 
 pub struct Pipeline {
-    github_client: reqwest::Client,
+    github_client: GithubClient,
+
+    // Name of the pipeline, used to tell pipelines of the same branch apart
+    name: String,
+
+    // Branch this pipeline builds, or the base branch of the pull request it builds
+    branch: String,
+
+    // Number of the pull request this pipeline builds, if it builds one
+    pull_request: Option<u64>,
 
     // Directory where this repo is cloned & built
     // Must not be shared with a second pipeline
@@ -22,37 +67,74 @@ pub struct Pipeline {
     // Name of the asset as it is uploaded into the GitHub release
     asset_name: String,
 
+    // {web_base_url}/{repo_owner}/{repo_name}
     repo_url: String,
-    // https://github.com/{repo_owner}/{repo_name}
-    repo_owner: String,
-    // https://github.com/{repo_owner}/{repo_name}
-    repo_name: String,
 
-    release_id: i64,
+    destination: Destination,
+
+    // Move the release's tag to the built commit after a successful upload
+    move_tag: bool,
 
-    pre_cmake_commands: Vec<String>,
+    // Update the release's name & body after a successful upload
+    release_notes: bool,
 
-    cmake_command: String,
+    // Templates for the release's name & body
+    release_name: String,
+    release_body: String,
 
-    pre_package_commands: Vec<String>,
+    pre_cmake_commands: Vec<Command>,
+
+    // The cmake program & its arguments
+    cmake_command: Vec<String>,
+
+    // Number of parallel jobs passed to `cmake --build`
+    parallelism: usize,
+
+    pre_package_commands: Vec<Command>,
 
     package_envs: HashMap<String, String>,
 
-    pre_dmg_commands: Vec<String>,
+    pre_dmg_commands: Vec<Command>,
+
+    // Extra environment variables for the commands of each step
+    step_envs: HashMap<Step, HashMap<String, String>>,
+
+    state: Arc<State>,
+
+    last_build: Mutex<Option<BuildRecord>>,
+
+    // Reuse the build directory between builds
+    incremental: bool,
+
+    // Maximum time between two clean builds when building incrementally
+    clean_build_interval: Option<Duration>,
+
+    // Report the progress & outcome of builds as a check run on the built commit
+    check_runs: bool,
+
+    // Skips pushes that only change files that aren't built
+    path_filter: Option<PathFilter>,
 }
 
 impl Pipeline {
     pub fn new(
-        github_client: reqwest::Client,
+        github_client: GithubClient,
+        state: Arc<State>,
         repo_dir: &str,
         dmg_output_path: &str,
+        web_base_url: &str,
         repo_owner: String,
         repo_name: String,
         branch: &crate::config::BranchAndRelease,
+        destination: Destination,
         default_cfg: &crate::config::DefaultBuild,
         mut cfg: crate::config::Build,
     ) -> Self {
         let mut cmake_command: Vec<String> = vec!["cmake".to_string()];
+        if let Some(generator) = cfg.generator.as_ref().or(default_cfg.generator.as_ref()) {
+            cmake_command.push("-G".into());
+            cmake_command.push(generator.clone());
+        }
         cmake_command.append(&mut default_cfg.cmake_args.clone());
         cmake_command.append(&mut cfg.cmake_args);
         cmake_command.push("..".into());
@@ -70,7 +152,23 @@ impl Pipeline {
 
         package_envs.insert("OUTPUT_DMG_PATH".to_string(), dmg_output_path.to_string());
 
-        let repo_url = format!("https://github.com/{repo_owner}/{repo_name}");
+        let mut step_envs: HashMap<Step, HashMap<String, String>> = HashMap::new();
+        for (step, envs) in default_cfg
+            .step_envs
+            .iter()
+            .chain(cfg.step_envs.iter())
+            .flatten()
+        {
+            step_envs.entry(*step).or_default().extend(
+                envs.iter()
+                    .map(|EnvironmentVariable { key, value }| (key.clone(), value.clone())),
+            );
+        }
+
+        let repo_url = format!(
+            "{}/{repo_owner}/{repo_name}",
+            web_base_url.trim_end_matches('/')
+        );
 
         // TODO: this shouldn't be hardcoded
         let repo_dir: PathBuf = repo_dir.into();
@@ -80,6 +178,10 @@ impl Pipeline {
         Self {
             github_client,
 
+            name: cfg.name.unwrap_or_else(|| cfg.asset_name.clone()),
+            branch: branch.name.clone(),
+            pull_request: None,
+
             repo_dir,
             build_dir,
             artifact_path,
@@ -87,22 +189,92 @@ impl Pipeline {
             asset_name: cfg.asset_name,
 
             repo_url,
-            repo_owner,
-            repo_name,
 
-            release_id: branch.release_id,
+            destination,
+
+            move_tag: branch.move_tag.unwrap_or(false),
+            release_notes: branch.release_notes.unwrap_or(false),
+            release_name: branch
+                .release_name
+                .clone()
+                .unwrap_or_else(|| DEFAULT_RELEASE_NAME.to_string()),
+            release_body: branch
+                .release_body
+                .clone()
+                .unwrap_or_else(|| DEFAULT_RELEASE_BODY.to_string()),
 
             pre_cmake_commands: cfg.pre_cmake_commands.map_or(vec![], |v| v),
-            cmake_command: cmake_command.join(" "),
+            cmake_command,
+            parallelism: cfg
+                .parallelism
+                .or(default_cfg.parallelism)
+                .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get())),
 
             pre_package_commands: cfg.pre_package_commands.map_or(vec![], |v| v),
             package_envs,
 
             pre_dmg_commands: cfg.pre_dmg_commands.map_or(vec![], |v| v),
+
+            step_envs,
+
+            state,
+
+            last_build: Mutex::new(None),
+
+            incremental: cfg.incremental.unwrap_or(false),
+            clean_build_interval: cfg
+                .clean_build_interval_hours
+                .map(|hours| Duration::from_secs(hours * 60 * 60)),
+
+            check_runs: cfg.check_runs.or(default_cfg.check_runs).unwrap_or(false),
+
+            path_filter: (cfg.include_paths.is_some() || cfg.exclude_paths.is_some()).then(|| {
+                PathFilter::new(cfg.include_paths.as_deref(), cfg.exclude_paths.as_deref())
+                    .expect("validated when reading the config")
+            }),
+        }
+    }
+
+    /// Makes this pipeline build the head of the given pull request instead of its branch.
+    /// The pull request's number is prepended to the asset name
+    pub fn for_pull_request(mut self, number: u64) -> Self {
+        self.pull_request = Some(number);
+        self.asset_name = format!("pr-{number}-{}", self.asset_name);
+        self
+    }
+
+    pub fn repo_dir(&self) -> &Path {
+        &self.repo_dir
+    }
+
+    // The branch or pull request this pipeline builds, e.g. "master" or "#123"
+    fn source(&self) -> String {
+        match self.pull_request {
+            Some(number) => format!("#{number}"),
+            None => self.branch.clone(),
         }
     }
 
-    async fn clone_and_checkout_repo(&self, force_reclone: bool) -> anyhow::Result<()> {
+    // Identifies this pipeline in logs & reports
+    pub fn display_name(&self) -> String {
+        format!(
+            "{} {} ({})",
+            self.github_client.repo_full_name(),
+            self.source(),
+            self.name
+        )
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn asset_name(&self) -> &str {
+        &self.asset_name
+    }
+
+    /// Clones or updates the repo, returning the commit hash that was checked out
+    async fn clone_and_checkout_repo(&self, force_reclone: bool) -> anyhow::Result<String> {
         if force_reclone {
             if let Err(e) = std::fs::remove_dir_all(&self.repo_dir) {
                 // Don't error out if the directory we want to delete doesn't exist
@@ -114,135 +286,1325 @@ impl Pipeline {
             }
         }
 
-        if let Ok(repo) = git2::Repository::open(&self.repo_dir) {
-            info!("Using already-existing repo");
-            {
-                let mut remote = repo.find_remote("origin")?;
-                info!("Repo remote: {:?}", remote.name());
-
-                let fetch_commit = crate::git::fetch(&repo, &mut remote)?;
-
-                // repo.merge(&[&fetch_commit], None, None)?;
+        let repo = if let Some(number) = self.pull_request {
+            let repo = match git2::Repository::open(&self.repo_dir) {
+                Ok(repo) => repo,
+                Err(_) => {
+                    info!("Cloning to {:?}", self.repo_dir);
+                    std::fs::create_dir_all(&self.repo_dir)?;
+                    crate::git::clone(&self.repo_url, &self.repo_dir, &self.branch)?
+                }
+            };
 
-                crate::git::merge(&repo, "master", fetch_commit)?;
-            }
+            info!("Checking out pull request #{number}");
+            crate::git::checkout_pull_request(&repo, number)?;
+            repo
+        } else if let Ok(repo) = git2::Repository::open(&self.repo_dir) {
+            info!("Using already-existing repo");
+            crate::git::checkout_branch(&repo, &self.branch)?;
+            repo
         } else {
             info!("Cloning to {:?}", self.repo_dir);
             std::fs::create_dir_all(&self.repo_dir)?;
-            git2::Repository::clone_recurse(&self.repo_url, &self.repo_dir)?;
+            let repo = crate::git::clone(&self.repo_url, &self.repo_dir, &self.branch)?;
             info!("Cloned to {:?}", self.repo_dir);
+            repo
+        };
+
+        Ok(crate::git::head_commit(&repo)?)
+    }
+
+    /// Returns the environment variables the commands of the given step run with
+    fn envs(&self, step: Step, ctx: &BuildContext) -> HashMap<String, String> {
+        let mut envs = HashMap::from([
+            ("ARTIFACT_BUILDER_BRANCH".to_string(), self.branch.clone()),
+            (
+                "ARTIFACT_BUILDER_COMMIT_SHA".to_string(),
+                ctx.commit_sha.clone(),
+            ),
+            (
+                "ARTIFACT_BUILDER_SHORT_SHA".to_string(),
+                ctx.short_sha().to_string(),
+            ),
+            ("ARTIFACT_BUILDER_BUILD_ID".to_string(), ctx.id.clone()),
+            (
+                "ARTIFACT_BUILDER_BUILD_NUMBER".to_string(),
+                ctx.number.to_string(),
+            ),
+            ("ARTIFACT_BUILDER_PIPELINE".to_string(), self.name.clone()),
+            (
+                "ARTIFACT_BUILDER_PULL_REQUEST".to_string(),
+                self.pull_request.map_or(String::new(), |n| n.to_string()),
+            ),
+            // Absolute, since the commands don't run in the directory the config is relative to
+            (
+                "ARTIFACT_BUILDER_REPO_DIR".to_string(),
+                absolute(&self.repo_dir),
+            ),
+            (
+                "ARTIFACT_BUILDER_BUILD_DIR".to_string(),
+                absolute(&self.build_dir),
+            ),
+        ]);
+
+        if matches!(step, Step::Package | Step::Dmg) {
+            envs.extend(self.package_envs.clone());
+        }
+
+        if let Some(step_envs) = self.step_envs.get(&step) {
+            envs.extend(step_envs.clone());
+        }
+
+        envs
+    }
+
+    // Identifies this pipeline in the persisted state
+    fn state_key(&self) -> String {
+        format!(
+            "{}/{}/{}",
+            self.github_client.repo_full_name(),
+            self.source(),
+            self.name
+        )
+    }
+
+    fn next_build_number(&self) -> anyhow::Result<u64> {
+        self.state.build_numbers.update(|numbers| {
+            let number = numbers.entry(self.state_key()).or_default();
+            *number += 1;
+            *number
+        })
+    }
+
+    /// Remembers whether the artifact built from the given commit still needs to be uploaded
+    fn set_pending_upload(&self, commit_sha: &str, pending: bool) {
+        let res = self.state.pending_uploads.update(|pending_uploads| {
+            if pending {
+                pending_uploads.insert(self.state_key(), commit_sha.to_string());
+            } else {
+                pending_uploads.remove(&self.state_key());
+            }
+        });
+
+        if let Err(e) = res {
+            warn!("Failed saving pending upload state: {e:#}");
+        }
+    }
+
+    /// Returns true if the artifact of the given commit was built, but not uploaded yet
+    fn has_pending_upload(&self, commit_sha: &str) -> bool {
+        let pending = self.state.pending_uploads.read(|pending_uploads| {
+            pending_uploads.get(&self.state_key()).map(String::as_str) == Some(commit_sha)
+        });
+
+        pending && self.artifact_path.exists()
+    }
+
+    fn clean_build_reason(
+        &self,
+        request: &BuildRequest,
+        fingerprint: &str,
+    ) -> Option<&'static str> {
+        if request.clean {
+            return Some("clean build requested");
+        }
+
+        if !self.incremental {
+            return Some("incremental builds are disabled");
+        }
+
+        incremental::clean_build_reason(
+            BuildDirState::load(&self.build_dir).as_ref(),
+            fingerprint,
+            self.clean_build_interval,
+        )
+    }
+
+    /// Returns the commands that build the asset, in the order they run
+    fn commands(&self) -> Vec<(Step, Command)> {
+        let mut commands = vec![];
+
+        commands.extend(
+            self.pre_cmake_commands
+                .iter()
+                .map(|c| (Step::PreCmake, c.clone())),
+        );
+        commands.push((Step::Cmake, Command::Argv(self.cmake_command.clone())));
+        commands.push((
+            Step::Compile,
+            Command::Argv(vec![
+                "cmake".into(),
+                "--build".into(),
+                ".".into(),
+                "--parallel".into(),
+                self.parallelism.to_string(),
+            ]),
+        ));
+        commands.extend(
+            self.pre_package_commands
+                .iter()
+                .map(|c| (Step::PrePackage, c.clone())),
+        );
+        commands.push((Step::Package, self.ci_script("MacDeploy.sh")));
+        commands.extend(
+            self.pre_dmg_commands
+                .iter()
+                .map(|c| (Step::PreDmg, c.clone())),
+        );
+        commands.push((Step::Dmg, self.ci_script("CreateDMG.sh")));
+
+        commands
+    }
+
+    /// Returns a command running a script of the repo's .CI directory. The script is given with
+    /// an absolute path, since relative program paths aren't resolved the same way on every platform
+    fn ci_script(&self, name: &str) -> Command {
+        Command::Argv(vec![absolute(&self.repo_dir.join(".CI").join(name))])
+    }
+
+    async fn build_asset(
+        &self,
+        request: &BuildRequest,
+        ctx: &BuildContext,
+        record: &mut BuildRecord,
+        check_run: Option<&CheckRun>,
+    ) -> Result<(), BuildError> {
+        let started = Instant::now();
+        let stage_error = |e: anyhow::Error| BuildError::other(Stage::Build, started.elapsed(), &e);
+
+        let fingerprint = incremental::fingerprint(&self.cmake_command).await;
+        let mut state = BuildDirState::load(&self.build_dir);
+
+        match self.clean_build_reason(request, &fingerprint) {
+            Some(reason) => {
+                info!("Doing a clean build: {reason}");
+
+                if let Err(e) = std::fs::remove_dir_all(&self.build_dir) {
+                    // Don't error out if the directory we want to delete doesn't exist
+                    if e.kind() != std::io::ErrorKind::NotFound {
+                        return Err(stage_error(
+                            anyhow::anyhow!(e).context("Deleting the build directory"),
+                        ));
+                    }
+                }
+
+                state = Some(BuildDirState {
+                    fingerprint,
+                    last_clean_build: now(),
+                });
+            }
+            None => {
+                info!("Reusing the build directory for an incremental build");
+            }
+        }
+
+        std::fs::create_dir_all(&self.build_dir)
+            .context("Creating the build directory")
+            .map_err(stage_error)?;
+
+        for (step, command) in self.commands() {
+            if let Some(check_run) = check_run {
+                check_run
+                    .progress(format!("Running {step}"), format!("Running `{command}`"))
+                    .await;
+            }
+
+            let step_started = Instant::now();
+
+            let res = run_command(&command, &self.build_dir, Some(&self.envs(step, ctx))).await;
+
+            record.steps.push(StepTiming {
+                step,
+                command: command.to_string(),
+                duration: step_started.elapsed(),
+            });
+
+            res.map_err(|e| BuildError {
+                stage: Stage::Build,
+                step: Some(step),
+                command: Some(command.to_string()),
+                kind: e.kind,
+                duration: step_started.elapsed(),
+                output: e.output,
+                diagnostics: e.diagnostics,
+            })?;
+        }
+
+        // Only remember the build directory state once it's known to produce a working build
+        if let Some(state) = state {
+            state
+                .save(&self.build_dir)
+                .context("Saving build directory state")
+                .map_err(stage_error)?;
         }
 
         Ok(())
     }
 
-    async fn build_asset(&self) -> anyhow::Result<()> {
-        if let Err(e) = std::fs::remove_dir_all(&self.build_dir) {
-            // Don't error out if the directory we want to delete doesn't exist
-            if e.kind() != std::io::ErrorKind::NotFound {
-                return Err(anyhow::anyhow!(e));
+    // Name the asset is uploaded under before it replaces the old asset
+    fn temporary_asset_name(&self, ctx: &BuildContext) -> String {
+        format!("tmp-{}-{}", ctx.number, self.asset_name)
+    }
+
+    /// Looks up the release this pipeline uploads to. Returns None if it's referred to by a tag
+    /// that has no release yet
+    pub async fn find_release(&self) -> anyhow::Result<Option<GetReleaseRoot>> {
+        let release = match &self.destination {
+            Destination::LocalStore { .. } => {
+                anyhow::bail!("{} doesn't upload to a release", self.display_name())
+            }
+            Destination::Release(ReleaseRef::Id(release_id)) => Some(
+                self.github_client
+                    .get_release(*release_id)
+                    .await
+                    .context(format!("getting release {release_id}"))?,
+            ),
+            Destination::Release(ReleaseRef::Tag { tag, .. }) => {
+                match self.github_client.get_release_by_tag(tag).await {
+                    Ok(release) => Some(release),
+                    Err(crate::github::Error::NotFound(_)) => None,
+                    Err(e) => {
+                        return Err(anyhow::anyhow!(e).context(format!("getting release {tag}")))
+                    }
+                }
+            }
+        };
+
+        Ok(release)
+    }
+
+    /// Looks up the release this pipeline uploads to, creating it if it's referred to by a tag
+    /// that has no release yet
+    pub async fn resolve_release(&self) -> anyhow::Result<GetReleaseRoot> {
+        let release = match &self.destination {
+            Destination::LocalStore { .. } => {
+                anyhow::bail!("{} doesn't upload to a release", self.display_name())
+            }
+            Destination::Release(ReleaseRef::Id(release_id)) => self
+                .github_client
+                .get_release(*release_id)
+                .await
+                .context(format!("getting release {release_id}"))?,
+            Destination::Release(ReleaseRef::Tag { tag, prerelease }) => self
+                .github_client
+                .find_or_create_release(tag, &self.branch, *prerelease)
+                .await
+                .context(format!("getting or creating release {tag}"))?,
+        };
+
+        Ok(release)
+    }
+
+    /// Deletes temporary & replaced assets of this pipeline that earlier builds failed to clean up
+    async fn delete_stale_temporary_assets(&self, release_id: i64) -> anyhow::Result<()> {
+        let release = self.github_client.get_release(release_id).await?;
+
+        for asset in release.assets {
+            let is_temporary = asset
+                .name
+                .strip_prefix("tmp-")
+                .or_else(|| asset.name.strip_prefix("old-"))
+                .and_then(|name| name.split_once('-'))
+                .is_some_and(|(number, name)| {
+                    number.parse::<u64>().is_ok() && name == self.asset_name
+                });
+
+            if is_temporary {
+                info!("Deleting stale temporary asset {}", asset.name);
+                self.github_client.delete_release_asset(asset.id).await?;
             }
         }
 
-        std::fs::create_dir_all(&self.build_dir)?;
-        std::env::set_current_dir(&self.build_dir)?;
+        Ok(())
+    }
+
+    /// Replaces the asset in the release with the newly built artifact.
+    /// The release keeps a working download if any of the steps fail:
+    /// 1. The artifact is uploaded under a temporary name, next to the old asset
+    /// 2. The old asset is renamed aside
+    /// 3. The new asset is renamed to the asset name. If that fails, the old asset is renamed back
+    /// 4. The old asset is deleted
+    async fn replace_asset(
+        &self,
+        release_id: i64,
+        ctx: &BuildContext,
+    ) -> anyhow::Result<ReleaseAsset> {
+        let temporary_name = self.temporary_asset_name(ctx);
+        let replaced_name = format!("old-{}-{}", ctx.number, self.asset_name);
+
+        let new_asset = self
+            .github_client
+            .upload_release_asset(release_id, &self.artifact_path, &temporary_name)
+            .await
+            .context(format!("uploading asset as {temporary_name}"))?;
+
+        let old_asset = match self
+            .github_client
+            .find_release_asset(release_id, &self.asset_name)
+            .await
+            .context(format!("finding old asset with name {}", self.asset_name))
+        {
+            Ok(Some(old_asset)) => {
+                info!("Found old release asset with ID {}", old_asset.id);
+                self.github_client
+                    .rename_release_asset(old_asset.id, &replaced_name)
+                    .await
+                    .map(|_| Some(old_asset))
+                    .context(format!("renaming old asset to {replaced_name}"))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        };
+
+        let old_asset = match old_asset {
+            Ok(old_asset) => old_asset,
+            Err(e) => {
+                // The old asset is still in place, so get rid of the new one instead
+                if let Err(e) = self.github_client.delete_release_asset(new_asset.id).await {
+                    warn!("Failed deleting temporary asset {temporary_name}: {e}");
+                }
+
+                return Err(e);
+            }
+        };
+
+        let asset = match self
+            .github_client
+            .rename_release_asset(new_asset.id, &self.asset_name)
+            .await
+            .context(format!("renaming {temporary_name} to {}", self.asset_name))
+        {
+            Ok(asset) => asset,
+            Err(e) => {
+                // Put the old asset back, the new one stays available under its temporary name
+                if let Some(old_asset) = &old_asset {
+                    if let Err(e) = self
+                        .github_client
+                        .rename_release_asset(old_asset.id, &self.asset_name)
+                        .await
+                    {
+                        warn!("Failed renaming {replaced_name} back: {e}");
+                    }
+                }
+
+                return Err(e);
+            }
+        };
+
+        if let Some(old_asset) = old_asset {
+            // If this fails, the old asset is cleaned up by the next build
+            if let Err(e) = self.github_client.delete_release_asset(old_asset.id).await {
+                warn!("Failed deleting old asset {replaced_name}: {e}");
+            }
+        }
 
-        for command in &self.pre_cmake_commands {
-            run_command(command, None).await?;
+        // Only clean up now, if an earlier rename failed its temporary asset was the only download
+        if let Err(e) = self.delete_stale_temporary_assets(release_id).await {
+            warn!("Failed deleting stale temporary assets: {e:#}");
         }
 
-        run_command(&self.cmake_command, None).await?;
+        Ok(asset)
+    }
+
+    /// Renders the commits between `before` and the built commit as a markdown list.
+    /// If `before` isn't in the clone, e.g. after a force push, the commits since the last uploaded
+    /// artifact are listed instead
+    fn changelog(&self, before: &str, ctx: &BuildContext) -> anyhow::Result<String> {
+        let repo = git2::Repository::open(&self.repo_dir)?;
+
+        let commits = match crate::git::commit_range(&repo, before, &ctx.commit_sha) {
+            Err(e) if e.code() == git2::ErrorCode::NotFound => {
+                let last_built = self
+                    .state
+                    .built_commits
+                    .read(|built_commits| built_commits.get(&self.state_key()).cloned());
+
+                match last_built
+                    .map(|last_built| crate::git::commit_range(&repo, &last_built, &ctx.commit_sha))
+                {
+                    Some(Ok(commits)) => {
+                        info!(
+                            "{before} isn't in the clone, listing the commits since the last build"
+                        );
+                        commits
+                    }
+                    _ => {
+                        return Ok(format!(
+                            "- The branch was force-pushed, the commits since {} are unknown",
+                            before.get(..7).unwrap_or(before)
+                        ))
+                    }
+                }
+            }
+            commits => commits?,
+        };
 
-        run_command("make -j8", None).await?;
+        let mut changelog: Vec<String> = commits
+            .iter()
+            .take(CHANGELOG_MAX_COMMITS)
+            .map(|c| format!("- {} {} ({})", &c.sha[..7], c.subject, c.author))
+            .collect();
 
-        for command in &self.pre_package_commands {
-            run_command(command, None).await?;
+        if commits.len() > CHANGELOG_MAX_COMMITS {
+            changelog.push(format!(
+                "- ... and {} more",
+                commits.len() - CHANGELOG_MAX_COMMITS
+            ));
         }
 
-        run_command("../.CI/MacDeploy.sh", Some(&self.package_envs)).await?;
+        Ok(changelog.join("\n"))
+    }
+
+    /// Remembers the commit the uploaded artifact was built from
+    fn set_built_commit(&self, commit_sha: &str) {
+        let res = self.state.built_commits.update(|built_commits| {
+            built_commits.insert(self.state_key(), commit_sha.to_string());
+        });
 
-        for command in &self.pre_dmg_commands {
-            run_command(command, None).await?;
+        if let Err(e) = res {
+            warn!("Failed saving the built commit: {e:#}");
         }
+    }
 
-        run_command("../.CI/CreateDMG.sh", Some(&self.package_envs)).await?;
+    /// Moves the release's tag to the built commit
+    async fn move_release_tag(
+        &self,
+        release: &GetReleaseRoot,
+        ctx: &BuildContext,
+    ) -> anyhow::Result<()> {
+        info!("Moving tag {} to {}", release.tag_name, ctx.commit_sha);
+        self.github_client
+            .update_tag(&release.tag_name, &ctx.commit_sha)
+            .await
+            .context(format!("moving tag {}", release.tag_name))?;
 
         Ok(())
     }
 
-    async fn delete_old_asset(&self) -> anyhow::Result<()> {
-        // 1. Delete the macOS asset if it already exists
-        let old_macos_release_asset = github::find_macos_asset(
-            self.github_client.clone(),
-            &self.repo_owner,
-            &self.repo_name,
-            self.release_id,
-            &self.asset_name,
-        )
-        .await
-        .context(format!("finding old asset with name {}", self.asset_name))?;
-
-        if let Some(asset_id) = old_macos_release_asset {
-            info!("Found old release asset with ID {asset_id}");
-            github::delete_github_asset(
-                self.github_client.clone(),
-                &self.repo_owner,
-                &self.repo_name,
-                asset_id,
+    /// Updates the release's name & body to describe the built commit
+    async fn update_release(
+        &self,
+        release: &GetReleaseRoot,
+        request: &BuildRequest,
+        ctx: &BuildContext,
+    ) -> anyhow::Result<()> {
+        // The range is only known for pushes, the changelog is left empty for other builds
+        let changelog = match &request.before {
+            Some(before) => self.changelog(before, ctx).unwrap_or_else(|e| {
+                warn!("Failed listing the commits since {before}: {e:#}");
+                String::new()
+            }),
+            None => String::new(),
+        };
+
+        let vars = HashMap::from([
+            ("tag", release.tag_name.clone()),
+            ("branch", self.branch.clone()),
+            ("commit", ctx.commit_sha.clone()),
+            ("short_sha", ctx.short_sha().to_string()),
+            ("build_number", ctx.number.to_string()),
+            ("date", template::format_date(now())),
+            ("changelog", changelog),
+        ]);
+
+        self.github_client
+            .update_release(
+                release.id,
+                template::render(&self.release_name, &vars).trim(),
+                template::render(&self.release_body, &vars).trim(),
             )
             .await
-            .context(format!("deleting old asset with name {}", self.asset_name))?;
+            .context(format!("updating release {}", release.id))?;
+
+        Ok(())
+    }
+
+    /// Copies the artifact into the local store, returning the URL it's served at
+    fn copy_to_local_store(&self, dir: &Path, url: &str) -> anyhow::Result<String> {
+        std::fs::create_dir_all(dir).context("Creating the artifact directory")?;
+
+        // Copy next to the old artifact first, so it's replaced in a single step
+        let temporary_path = dir.join(format!(".{}.tmp", self.asset_name));
+        std::fs::copy(&self.artifact_path, &temporary_path).context("Copying the artifact")?;
+        std::fs::rename(&temporary_path, dir.join(&self.asset_name))
+            .context("Replacing the old artifact")?;
+
+        Ok(format!("{url}/{}", self.asset_name))
+    }
+
+    /// Adds or updates this pipeline's line in the builder's comment on the pull request
+    async fn update_pull_request_comment(
+        &self,
+        number: u64,
+        record: &BuildRecord,
+    ) -> anyhow::Result<()> {
+        // Only the builder's own comment is updated, anyone could've written one with the marker
+        let comments = self.github_client.list_own_issue_comments(number).await?;
+        let existing = comments.iter().find(|c| {
+            c.body
+                .as_deref()
+                .is_some_and(|body| body.starts_with(pull_request::COMMENT_MARKER))
+        });
+
+        let body = pull_request::comment_body(
+            existing.and_then(|c| c.body.as_deref()),
+            &self.name,
+            &pull_request::comment_line(&self.asset_name, record),
+        );
+
+        match existing {
+            Some(comment) => {
+                self.github_client
+                    .update_issue_comment(comment.id, &body)
+                    .await?;
+            }
+            None => {
+                self.github_client
+                    .create_issue_comment(number, &body)
+                    .await?;
+            }
         }
+
         Ok(())
     }
 
-    async fn upload_asset(&self) -> anyhow::Result<UploadReleaseAssetRoot> {
-        // TODO: Add retry mechanics
-        let release_asset = github::upload_asset_to_github_release(
-            self.github_client.clone(),
-            &self.repo_owner,
-            &self.repo_name,
-            self.release_id,
-            &self.artifact_path,
-            &self.asset_name,
-        )
-        .await
-        .context("Uploading macOS asset")?;
+    /// Returns the record of the most recent build of this pipeline
+    pub fn last_build(&self) -> Option<BuildRecord> {
+        self.last_build.lock().unwrap().clone()
+    }
+
+    /// Returns why the commit isn't built because of the path filters, or None if it's built.
+    /// Commits are always built if it's unknown which files changed, e.g. for a new branch
+    fn skip_reason(&self, request: &BuildRequest, commit_sha: &str) -> Option<String> {
+        let path_filter = self.path_filter.as_ref()?;
+        let before = request.before.as_deref()?;
+
+        let changed_files = match &request.changed_files {
+            // The files listed in the push only apply if the branch didn't move on since
+            Some(files) if request.commit.as_deref() == Some(commit_sha) => files.clone(),
+            _ => {
+                let files = git2::Repository::open(&self.repo_dir)
+                    .and_then(|repo| crate::git::changed_files(&repo, before, commit_sha));
+
+                match files {
+                    Ok(files) => files,
+                    Err(e) => {
+                        warn!(
+                            "Failed listing the files changed since {before}, building anyway: {e}"
+                        );
+                        return None;
+                    }
+                }
+            }
+        };
 
-        Ok(release_asset)
+        path_filter.skip_reason(&changed_files)
     }
 
-    // TODO: This should include commit hash
     // TODO: This should fire off a build event into a queue instead of just immediately building
-    pub async fn build(&self) -> anyhow::Result<()> {
-        match self
+    pub async fn build(&self, request: &BuildRequest) -> Result<(), BuildError> {
+        let mut record =
+            BuildRecord::new(uuid::Uuid::new_v4().to_string(), &self.name, &self.source());
+        record.delivery_id = request.delivery_id.clone();
+        *self.last_build.lock().unwrap() = Some(record.clone());
+
+        let mut check_run = None;
+        let res = self.run(request, &mut record, &mut check_run).await;
+
+        record.finish(res.as_ref().map(|_| ()));
+
+        if let Some(check_run) = check_run {
+            check_run
+                .finish(&record, &self.repo_dir, &self.build_dir)
+                .await;
+        }
+
+        if let Some(number) = self.pull_request {
+            if let Err(e) = self.update_pull_request_comment(number, &record).await {
+                warn!("Failed updating the comment on pull request #{number}: {e:#}");
+            }
+        }
+
+        *self.last_build.lock().unwrap() = Some(record);
+
+        res
+    }
+
+    async fn run(
+        &self,
+        request: &BuildRequest,
+        record: &mut BuildRecord,
+        check_run: &mut Option<CheckRun>,
+    ) -> Result<(), BuildError> {
+        let started = Instant::now();
+        let commit_sha = match self
             .clone_and_checkout_repo(false)
             .await
             .context("Cloning & checking out repo")
         {
-            Ok(_) => {
-                // cloned successfully
-            }
+            Ok(commit_sha) => commit_sha,
             Err(e) => {
                 error!("Failed cloning the repo: {e}");
                 info!("Retrying the clone");
 
                 self.clone_and_checkout_repo(true)
                     .await
-                    .context("Cloning & checking out repo for the second time")?;
+                    .context("Cloning & checking out repo for the second time")
+                    .map_err(|e| BuildError::other(Stage::Checkout, started.elapsed(), &e))?
+            }
+        };
+
+        if let Some(requested_commit) = &request.commit {
+            if *requested_commit != commit_sha {
+                info!("Branch moved on from {requested_commit}, building {commit_sha} instead");
+            }
+        }
+
+        record.commit_sha = Some(commit_sha.clone());
+        *self.last_build.lock().unwrap() = Some(record.clone());
+
+        if !request.clean && !request.force {
+            if let Some(reason) = self.skip_reason(request, &commit_sha) {
+                info!("Skipping the build of {commit_sha}: {reason}");
+                record.skip_reason = Some(reason);
+                return Ok(());
+            }
+        }
+
+        // Only builds that run get a number, so the numbers of a pipeline have no gaps
+        let number = self
+            .next_build_number()
+            .context("Saving build number")
+            .map_err(|e| BuildError::other(Stage::Checkout, started.elapsed(), &e))?;
+        record.number = Some(number);
+
+        let ctx = BuildContext {
+            id: record.id.clone(),
+            number,
+            commit_sha,
+        };
+        info!(
+            "Starting build #{} ({}) of {} at {}",
+            ctx.number, ctx.id, self.name, ctx.commit_sha
+        );
+
+        if self.check_runs {
+            *check_run = CheckRun::start(
+                &self.github_client,
+                &format!("artifact-builder / {}", self.name),
+                &ctx.commit_sha,
+            )
+            .await;
+        }
+
+        if !request.clean && self.has_pending_upload(&ctx.commit_sha) {
+            info!("The artifact of this commit was built already, retrying its upload");
+        } else {
+            self.build_asset(request, &ctx, record, check_run.as_ref())
+                .await?;
+        }
+
+        record.artifact_size = std::fs::metadata(&self.artifact_path).ok().map(|m| m.len());
+
+        if let Some(check_run) = check_run {
+            check_run
+                .progress(
+                    "Uploading".to_string(),
+                    format!("Uploading `{}`", self.asset_name),
+                )
+                .await;
+        }
+
+        let started = Instant::now();
+
+        let upload = match &self.destination {
+            // The release is looked up for every build, since a nightly release might've been recreated
+            Destination::Release(_) => match self.resolve_release().await {
+                Ok(release) => self
+                    .replace_asset(release.id, &ctx)
+                    .await
+                    .map(|asset| (Some(release), asset.browser_download_url)),
+                Err(e) => Err(e),
+            },
+            Destination::LocalStore { dir, url } => self
+                .copy_to_local_store(dir, url)
+                .map(|artifact_url| (None, artifact_url)),
+        };
+
+        let (release, artifact_url) = match upload.context("Uploading asset") {
+            Ok(upload) => upload,
+            Err(e) => {
+                // Keep the artifact around so the next build of this commit only retries the upload
+                self.set_pending_upload(&ctx.commit_sha, true);
+
+                return Err(BuildError::other(Stage::Upload, started.elapsed(), &e));
             }
         };
 
-        self.build_asset().await.context("Building asset")?;
+        self.set_pending_upload(&ctx.commit_sha, false);
+        record.artifact_url = Some(artifact_url);
 
-        if let Err(e) = self.delete_old_asset().await.context("Deleting old asset") {
-            warn!("Failed deleting old asset, upload might fail? {e:?}");
+        let Some(release) = release else {
+            self.set_built_commit(&ctx.commit_sha);
+            info!("Done!");
+            return Ok(());
+        };
+
+        // The asset is in place already, so these don't fail the build
+        if self.move_tag {
+            if let Err(e) = self.move_release_tag(&release, &ctx).await {
+                warn!("Failed moving the release tag: {e:#}");
+            }
         }
 
-        self.upload_asset().await.context("Uploading asset")?;
+        if self.move_tag || self.release_notes {
+            if let Err(e) = self.update_release(&release, request, &ctx).await {
+                warn!("Failed updating the release: {e:#}");
+            }
+        }
 
+        // Only now, so the changelog can fall back to the previously built commit
+        self.set_built_commit(&ctx.commit_sha);
         info!("Done!");
 
         Ok(())
     }
 }
+
+/// Returns the path relative to the current directory as an absolute path
+fn absolute(path: &Path) -> String {
+    std::path::absolute(path)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use wiremock::{
+        matchers::{body_json, method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+    use crate::build::{BuildStatus, Jobs};
+    use crate::config::{BranchAndRelease, DefaultBuild};
+    use crate::github::client::testing::{asset, client, release};
+
+    const ASSET_NAME: &str = "Chatterino.dmg";
+
+    /// Creates a pipeline building master of owner/repo, which is cloned from a local repo
+    /// inside `dir`. Returns the pipeline & the commit master points to
+    fn pipeline(server: &MockServer, dir: &Path) -> (Pipeline, String) {
+        pipeline_with_config(server, dir, json!({}))
+    }
+
+    /// Same as `pipeline`, with the given fields of the build config set
+    fn pipeline_with_config(
+        server: &MockServer,
+        dir: &Path,
+        fields: serde_json::Value,
+    ) -> (Pipeline, String) {
+        pipeline_with_defaults(server, dir, json!({}), fields)
+    }
+
+    /// Same as `pipeline_with_config`, with the given fields of the default build config set
+    fn pipeline_with_defaults(
+        server: &MockServer,
+        dir: &Path,
+        default_fields: serde_json::Value,
+        fields: serde_json::Value,
+    ) -> (Pipeline, String) {
+        let origin_dir = dir.join("owner/repo");
+        let commit = match git2::Repository::open(&origin_dir) {
+            Ok(origin) => crate::git::head_commit(&origin).unwrap(),
+            Err(_) => crate::git::testing::commit(
+                &crate::git::testing::init(&origin_dir),
+                &[("CMakeLists.txt", Some("project(chatterino)"))],
+                "Initial commit",
+            ),
+        };
+
+        let branch = BranchAndRelease {
+            name: "master".to_string(),
+            release_id: None,
+            release_tag: Some("nightly-build".to_string()),
+            prerelease: Some(true),
+            move_tag: None,
+            release_notes: None,
+            release_name: None,
+            release_body: None,
+        };
+        let mut default_cfg = json!({
+            "cmake_args": [],
+            "package_envs": [],
+        });
+        default_cfg
+            .as_object_mut()
+            .unwrap()
+            .extend(default_fields.as_object().unwrap().clone());
+        let default_cfg: DefaultBuild = serde_json::from_value(default_cfg).unwrap();
+        let mut cfg = json!({
+            "cmake_args": [],
+            "package_envs": [],
+            "build_dir": "build",
+            "asset_name": ASSET_NAME,
+        });
+        cfg.as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        let cfg: crate::config::Build = serde_json::from_value(cfg).unwrap();
+
+        let pipeline = Pipeline::new(
+            client(server),
+            Arc::new(State::open(&dir.join("state")).unwrap()),
+            dir.join("clone").to_str().unwrap(),
+            "chatterino.dmg",
+            &format!("file://{}", dir.display()),
+            "owner".to_string(),
+            "repo".to_string(),
+            &branch,
+            Destination::Release(branch.release()),
+            &default_cfg,
+            cfg,
+        );
+
+        (pipeline, commit)
+    }
+
+    /// Clones the repo & puts a built artifact of the commit in place, so the pipeline only
+    /// uploads it
+    fn build_artifact(pipeline: &Pipeline, commit: &str) {
+        crate::git::clone(&pipeline.repo_url, &pipeline.repo_dir, "master").unwrap();
+        std::fs::create_dir_all(&pipeline.build_dir).unwrap();
+        std::fs::write(&pipeline.artifact_path, "dmg").unwrap();
+        pipeline.set_pending_upload(commit, true);
+    }
+
+    #[tokio::test]
+    async fn sets_step_envs() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = MockServer::start().await;
+        let (mut pipeline, commit) = pipeline_with_defaults(
+            &server,
+            dir.path(),
+            json!({
+                "package_envs": [{ "key": "Qt6_DIR", "value": "/opt/qt/default" }],
+                "step_envs": {
+                    "compile": [
+                        { "key": "CCACHE_DIR", "value": "/tmp/ccache" },
+                        { "key": "VERBOSE", "value": "0" },
+                    ],
+                },
+            }),
+            json!({
+                "package_envs": [{ "key": "Qt6_DIR", "value": "/opt/qt/6.5.0" }],
+                "step_envs": {
+                    "compile": [{ "key": "VERBOSE", "value": "1" }],
+                    "dmg": [{ "key": "Qt6_DIR", "value": "/opt/qt/dmg" }],
+                },
+            }),
+        );
+        // The clone directory is relative to the builder's working directory
+        pipeline.repo_dir = "clone".into();
+        pipeline.build_dir = pipeline.repo_dir.join("build");
+
+        let ctx = BuildContext {
+            id: "build-id".to_string(),
+            number: 4,
+            commit_sha: commit.clone(),
+        };
+        let cwd = std::env::current_dir().unwrap();
+
+        let envs = pipeline.envs(Step::Compile, &ctx);
+        assert_eq!(envs["ARTIFACT_BUILDER_BRANCH"], "master");
+        assert_eq!(envs["ARTIFACT_BUILDER_COMMIT_SHA"], commit);
+        assert_eq!(envs["ARTIFACT_BUILDER_SHORT_SHA"], commit[..7]);
+        assert_eq!(envs["ARTIFACT_BUILDER_BUILD_ID"], "build-id");
+        assert_eq!(envs["ARTIFACT_BUILDER_BUILD_NUMBER"], "4");
+        assert_eq!(envs["ARTIFACT_BUILDER_PIPELINE"], ASSET_NAME);
+        assert_eq!(envs["ARTIFACT_BUILDER_PULL_REQUEST"], "");
+        assert_eq!(
+            envs["ARTIFACT_BUILDER_REPO_DIR"],
+            cwd.join("clone").to_string_lossy()
+        );
+        assert_eq!(
+            envs["ARTIFACT_BUILDER_BUILD_DIR"],
+            cwd.join("clone/build").to_string_lossy()
+        );
+        assert_eq!(envs["CCACHE_DIR"], "/tmp/ccache");
+        assert_eq!(envs["VERBOSE"], "1");
+        assert!(!envs.contains_key("Qt6_DIR"));
+
+        let envs = pipeline.envs(Step::Package, &ctx);
+        assert_eq!(envs["Qt6_DIR"], "/opt/qt/6.5.0");
+        assert_eq!(envs["OUTPUT_DMG_PATH"], "chatterino.dmg");
+        assert!(!envs.contains_key("VERBOSE"));
+
+        let envs = pipeline.envs(Step::Dmg, &ctx);
+        assert_eq!(envs["Qt6_DIR"], "/opt/qt/dmg");
+    }
+
+    #[tokio::test]
+    async fn numbers_only_builds_that_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = MockServer::start().await;
+        let (pipeline, commit) = pipeline_with_config(
+            &server,
+            dir.path(),
+            json!({ "include_paths": ["src/**"], "pre_cmake_commands": ["false"] }),
+        );
+
+        let mut request = BuildRequest {
+            commit: Some(commit.clone()),
+            before: Some(commit),
+            changed_files: Some(vec!["README.md".to_string()]),
+            ..Default::default()
+        };
+        pipeline.build(&request).await.unwrap();
+        let record = pipeline.last_build().unwrap();
+        assert_eq!(record.status, BuildStatus::Skipped);
+        assert_eq!(record.number, None);
+
+        request.force = true;
+        pipeline.build(&request).await.unwrap_err();
+        assert_eq!(pipeline.last_build().unwrap().number, Some(1));
+    }
+
+    #[tokio::test]
+    async fn replaces_release_asset() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = MockServer::start().await;
+        let (pipeline, commit) = pipeline(&server, dir.path());
+        build_artifact(&pipeline, &commit);
+
+        Mock::given(method("GET"))
+            .and(path("/repos/owner/repo/releases/tags/nightly-build"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(release(vec![asset(10, ASSET_NAME)])),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/repos/owner/repo/releases/1/assets"))
+            .and(query_param("name", "tmp-1-Chatterino.dmg"))
+            .respond_with(
+                ResponseTemplate::new(201).set_body_json(asset(12, "tmp-1-Chatterino.dmg")),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        // Before the rename, then while cleaning up a temporary asset left behind by build #0
+        Mock::given(method("GET"))
+            .and(path("/repos/owner/repo/releases/1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(release(vec![
+                asset(10, ASSET_NAME),
+                asset(12, "tmp-1-Chatterino.dmg"),
+            ])))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/repos/owner/repo/releases/1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(release(vec![
+                asset(9, "tmp-0-Chatterino.dmg"),
+                asset(12, ASSET_NAME),
+            ])))
+            .expect(1)
+            .mount(&server)
+            .await;
+        // The old asset is only deleted once the new one took its name
+        Mock::given(method("PATCH"))
+            .and(path("/repos/owner/repo/releases/assets/10"))
+            .and(body_json(json!({ "name": "old-1-Chatterino.dmg" })))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(asset(10, "old-1-Chatterino.dmg")),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("PATCH"))
+            .and(path("/repos/owner/repo/releases/assets/12"))
+            .and(body_json(json!({ "name": ASSET_NAME })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(asset(12, ASSET_NAME)))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/repos/owner/repo/releases/assets/10"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/repos/owner/repo/releases/assets/9"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        pipeline.build(&BuildRequest::default()).await.unwrap();
+
+        let record = pipeline.last_build().unwrap();
+        assert_eq!(record.commit_sha.as_deref(), Some(commit.as_str()));
+        assert_eq!(
+            record.artifact_url.as_deref(),
+            Some("https://github.com/owner/repo/releases/download/nightly/Chatterino.dmg")
+        );
+        assert!(!pipeline.has_pending_upload(&commit));
+    }
+
+    #[tokio::test]
+    async fn keeps_the_old_asset_if_the_upload_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = MockServer::start().await;
+        let (pipeline, commit) = pipeline(&server, dir.path());
+        build_artifact(&pipeline, &commit);
+
+        Mock::given(method("GET"))
+            .and(path("/repos/owner/repo/releases/tags/nightly-build"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(release(vec![asset(10, ASSET_NAME)])),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/repos/owner/repo/releases/1/assets"))
+            .respond_with(ResponseTemplate::new(422))
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        pipeline.build(&BuildRequest::default()).await.unwrap_err();
+
+        // The next build of the commit retries the upload
+        assert!(pipeline.has_pending_upload(&commit));
+    }
+
+    #[tokio::test]
+    async fn restores_the_old_asset_if_the_rename_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = MockServer::start().await;
+        let (pipeline, commit) = pipeline(&server, dir.path());
+        build_artifact(&pipeline, &commit);
+
+        Mock::given(method("GET"))
+            .and(path("/repos/owner/repo/releases/tags/nightly-build"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(release(vec![asset(10, ASSET_NAME)])),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/repos/owner/repo/releases/1/assets"))
+            .respond_with(
+                ResponseTemplate::new(201).set_body_json(asset(12, "tmp-1-Chatterino.dmg")),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/repos/owner/repo/releases/1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(release(vec![
+                asset(10, ASSET_NAME),
+                asset(12, "tmp-1-Chatterino.dmg"),
+            ])))
+            .mount(&server)
+            .await;
+        Mock::given(method("PATCH"))
+            .and(path("/repos/owner/repo/releases/assets/10"))
+            .and(body_json(json!({ "name": "old-1-Chatterino.dmg" })))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(asset(10, "old-1-Chatterino.dmg")),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("PATCH"))
+            .and(path("/repos/owner/repo/releases/assets/12"))
+            .respond_with(ResponseTemplate::new(422))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("PATCH"))
+            .and(path("/repos/owner/repo/releases/assets/10"))
+            .and(body_json(json!({ "name": ASSET_NAME })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(asset(10, ASSET_NAME)))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        pipeline.build(&BuildRequest::default()).await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn lists_pushed_commits() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = MockServer::start().await;
+        let (pipeline, first) = pipeline(&server, dir.path());
+
+        let origin = git2::Repository::open(dir.path().join("owner/repo")).unwrap();
+        let second =
+            crate::git::testing::commit(&origin, &[("a.cpp", Some("a"))], "Add a\n\nDetails");
+        let last = crate::git::testing::commit(&origin, &[("b.cpp", Some("b"))], "Add b");
+        crate::git::clone(&pipeline.repo_url, &pipeline.repo_dir, "master").unwrap();
+
+        let ctx = BuildContext {
+            id: "id".to_string(),
+            number: 1,
+            commit_sha: last.clone(),
+        };
+
+        let changelog = pipeline.changelog(&first, &ctx).unwrap();
+        assert_eq!(
+            changelog,
+            format!(
+                "- {} Add b (Tester)\n- {} Add a (Tester)",
+                &last[..7],
+                &second[..7]
+            )
+        );
+
+        // A force push removed the commit the branch pointed to before
+        let missing = "0123456789abcdef0123456789abcdef01234567";
+        assert_eq!(
+            pipeline.changelog(missing, &ctx).unwrap(),
+            "- The branch was force-pushed, the commits since 0123456 are unknown"
+        );
+
+        pipeline.set_built_commit(&first);
+        assert_eq!(pipeline.changelog(missing, &ctx).unwrap(), changelog);
+    }
+
+    #[tokio::test]
+    async fn cancels_the_check_run_of_aborted_builds() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = MockServer::start().await;
+        let (slow, _) = pipeline_with_config(
+            &server,
+            dir.path(),
+            json!({ "name": "slow", "check_runs": true, "pre_cmake_commands": ["sleep 5"] }),
+        );
+        let (failing, _) = pipeline_with_config(
+            &server,
+            dir.path(),
+            json!({ "name": "failing", "pre_cmake_commands": ["false"] }),
+        );
+
+        Mock::given(method("POST"))
+            .and(path("/repos/owner/repo/check-runs"))
+            .respond_with(ResponseTemplate::new(201).set_body_json(json!({
+                "id": 7,
+                "name": "artifact-builder / slow",
+                "head_sha": "abc123",
+                "status": "in_progress",
+                "conclusion": null,
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("PATCH"))
+            .and(path("/repos/owner/repo/check-runs/7"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": 7,
+                "name": "artifact-builder / slow",
+                "head_sha": "abc123",
+                "status": "completed",
+                "conclusion": "cancelled",
+            })))
+            .mount(&server)
+            .await;
+
+        let jobs = Jobs::default();
+        jobs.spawn(vec![Arc::new(slow)], BuildRequest::default());
+
+        let check_run_updates = || async {
+            server
+                .received_requests()
+                .await
+                .unwrap()
+                .into_iter()
+                .filter(|r| r.method.as_str() == "PATCH")
+                .map(|r| serde_json::from_slice::<serde_json::Value>(&r.body).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        // Wait until the slow build runs its command
+        for _ in 0..100 {
+            if !check_run_updates().await.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        // A newer job in the same clone directory aborts the slow build
+        jobs.spawn(vec![Arc::new(failing)], BuildRequest::default());
+
+        let mut conclusion = None;
+        for _ in 0..100 {
+            conclusion = check_run_updates()
+                .await
+                .into_iter()
+                .find_map(|update| update.get("conclusion").cloned());
+            if conclusion.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        assert_eq!(conclusion, Some(json!("cancelled")));
+    }
+
+    #[tokio::test]
+    async fn reports_only_finished_builds() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = MockServer::start().await;
+        let (failing, _) = pipeline_with_config(
+            &server,
+            dir.path(),
+            json!({ "name": "failing", "pre_cmake_commands": ["false"] }),
+        );
+        let (slow, _) = pipeline_with_config(
+            &server,
+            dir.path(),
+            json!({ "name": "slow", "pre_cmake_commands": ["sleep 5"] }),
+        );
+        let repo_dir = slow.repo_dir().to_path_buf();
+
+        let built = Arc::new(Mutex::new(vec![]));
+        let jobs = Jobs::default();
+        jobs.spawn_all_then(
+            vec![
+                (vec![Arc::new(failing)], BuildRequest::default()),
+                (vec![Arc::new(slow)], BuildRequest::default()),
+            ],
+            {
+                let built = built.clone();
+                move |i| built.lock().unwrap().push(i)
+            },
+        );
+
+        // Wait until the failing build finished, while the slow one runs
+        for _ in 0..100 {
+            if !built.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        jobs.abort(&repo_dir);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(*built.lock().unwrap(), vec![0]);
+    }
+}
@@ -0,0 +1,428 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+#[allow(unused)]
+use tracing::log::*;
+
+use super::{pipeline::Destination, BuildRecord, BuildStatus, Pipeline, State};
+use crate::config::{self, BranchAndRelease, ReleaseRef};
+use crate::github::{model::PullRequestEvent, GithubClient};
+
+// Starts the builder's comment on a pull request, so it can be found again
+pub const COMMENT_MARKER: &str = "<!-- artifact-builder -->";
+
+/// Creates the pipelines that build pull requests on demand, one set per pull request.
+/// Each pull request is cloned into a directory of its own, so it's built in isolation
+pub struct PullRequests {
+    cfg: config::PullRequestConfig,
+    build_cfg: config::BuildConfig,
+    configs: Vec<config::Build>,
+
+    // Used to clone the repo
+    web_base_url: String,
+    repo_owner: String,
+    repo_name: String,
+
+    destination: Destination,
+
+    github_client: GithubClient,
+    state: Arc<State>,
+
+    pipelines: Mutex<HashMap<u64, Vec<Arc<Pipeline>>>>,
+}
+
+impl PullRequests {
+    /// Returns None if pull request builds aren't configured for the repository
+    pub fn new(
+        cfg: &config::Config,
+        repository: &config::RepositoryConfig,
+        github_client: GithubClient,
+        state: Arc<State>,
+    ) -> Option<Self> {
+        let pr_cfg = repository.pull_requests.clone()?;
+
+        let destination = match (&pr_cfg.release_tag, &pr_cfg.artifact_dir) {
+            (Some(tag), _) => Destination::Release(ReleaseRef::Tag {
+                tag: tag.clone(),
+                prerelease: true,
+            }),
+            (None, dir) => Destination::LocalStore {
+                dir: dir
+                    .clone()
+                    .expect("validated when reading the config")
+                    .into(),
+                url: format!(
+                    "{}{}/artifacts/{}/{}",
+                    pr_cfg
+                        .public_url
+                        .as_deref()
+                        .unwrap_or_default()
+                        .trim_end_matches('/'),
+                    cfg.web.base_url,
+                    repository.owner,
+                    repository.name
+                ),
+            },
+        };
+
+        Some(Self {
+            cfg: pr_cfg,
+            build_cfg: cfg.build.clone(),
+            configs: repository.configs.clone(),
+            web_base_url: cfg
+                .github
+                .web_base_url
+                .clone()
+                .unwrap_or_else(|| crate::github::client::DEFAULT_WEB_BASE_URL.to_string()),
+            repo_owner: repository.owner.clone(),
+            repo_name: repository.name.clone(),
+            destination,
+            github_client,
+            state,
+            pipelines: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Returns the directory local artifacts are kept in, if they aren't uploaded to a release
+    pub fn artifact_dir(&self) -> Option<&Path> {
+        match &self.destination {
+            Destination::LocalStore { dir, .. } => Some(dir),
+            Destination::Release(_) => None,
+        }
+    }
+
+    /// Returns the tag of the release artifacts are uploaded to, if they aren't kept locally
+    pub fn release_tag(&self) -> Option<&str> {
+        match &self.destination {
+            Destination::Release(ReleaseRef::Tag { tag, .. }) => Some(tag),
+            _ => None,
+        }
+    }
+
+    pub fn is_allowed(&self, event: &PullRequestEvent) -> bool {
+        is_allowed(&self.cfg, event)
+    }
+
+    /// Returns the directory the pull request is cloned & built in
+    pub fn repo_dir(&self, number: u64) -> PathBuf {
+        Path::new(&self.cfg.work_dir).join(format!("pr-{number}"))
+    }
+
+    /// Forgets the pipelines & the state of a closed pull request and deletes its clone.
+    /// Its build must have been aborted already
+    pub async fn close(&self, number: u64) -> anyhow::Result<()> {
+        self.pipelines.lock().unwrap().remove(&number);
+
+        // The pipelines of the pull request may not have been created since the builder started,
+        // so the state is found by the start of the pipelines' keys, {owner}/{repo}/#{number}/{pipeline}
+        let prefix = format!("{}/#{number}/", self.github_client.repo_full_name());
+        if let Err(e) = self.state.forget_pipelines(&prefix) {
+            warn!("Failed removing the state of pull request #{number}: {e:#}");
+        }
+
+        let repo_dir = self.repo_dir(number);
+        match tokio::fs::remove_dir_all(&repo_dir).await {
+            Ok(()) => info!("Deleted the clone of pull request #{number}"),
+            // It wasn't built
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(anyhow::anyhow!(e).context(format!("Deleting {repo_dir:?}"))),
+        }
+
+        Ok(())
+    }
+
+    /// Returns the pipelines building the given pull request, creating them the first time
+    pub fn pipelines(&self, event: &PullRequestEvent) -> Vec<Arc<Pipeline>> {
+        let number = event.number;
+        let mut pipelines = self.pipelines.lock().unwrap();
+
+        pipelines
+            .entry(number)
+            .or_insert_with(|| {
+                let branch = BranchAndRelease {
+                    name: event.pull_request.base.branch.clone(),
+                    release_id: None,
+                    release_tag: None,
+                    prerelease: None,
+                    move_tag: None,
+                    release_notes: None,
+                    release_name: None,
+                    release_body: None,
+                };
+                let repo_dir = self.repo_dir(number);
+
+                self.configs
+                    .iter()
+                    .map(|c| {
+                        Arc::new(
+                            Pipeline::new(
+                                self.github_client.clone(),
+                                self.state.clone(),
+                                &repo_dir.to_string_lossy(),
+                                &self.build_cfg.dmg_output_path,
+                                &self.web_base_url,
+                                self.repo_owner.clone(),
+                                self.repo_name.clone(),
+                                &branch,
+                                self.destination.clone(),
+                                &self.build_cfg.default_config,
+                                c.clone(),
+                            )
+                            .for_pull_request(number),
+                        )
+                    })
+                    .collect()
+            })
+            .clone()
+    }
+}
+
+/// Pull requests are built if their author or one of their labels is allowed.
+/// Adding a label only triggers a build if it's one of the allowed labels
+fn is_allowed(cfg: &config::PullRequestConfig, event: &PullRequestEvent) -> bool {
+    let allowed_authors = cfg.allowed_authors.as_deref().unwrap_or_default();
+    let allowed_labels = cfg.allowed_labels.as_deref().unwrap_or_default();
+
+    match event.action.as_str() {
+        "labeled" => event
+            .label
+            .as_ref()
+            .is_some_and(|label| allowed_labels.contains(&label.name)),
+        _ => {
+            allowed_authors.contains(&event.pull_request.user.login)
+                || event
+                    .pull_request
+                    .labels
+                    .iter()
+                    .any(|label| allowed_labels.contains(&label.name))
+        }
+    }
+}
+
+/// Describes the outcome of a pipeline's build in a line of the pull request comment
+pub fn comment_line(asset_name: &str, record: &BuildRecord) -> String {
+    let commit = record
+        .commit_sha
+        .as_deref()
+        .map_or("", |sha| &sha[..sha.len().min(7)]);
+
+    // Builds that are skipped or fail before they start have no number
+    let build = record
+        .number
+        .map_or("build".to_string(), |number| format!("build #{number}"));
+
+    match (record.status, &record.artifact_url) {
+        (BuildStatus::Succeeded, Some(url)) => format!(
+            "**{}**: [{asset_name}]({url}) built from {commit}",
+            record.pipeline
+        ),
+        (BuildStatus::Skipped, _) => {
+            format!("**{}**: {build} skipped at {commit}", record.pipeline)
+        }
+        _ => format!("**{}**: {build} failed at {commit}", record.pipeline),
+    }
+}
+
+/// Returns the new body of the builder's pull request comment, with the pipeline's line replaced
+/// or added. Each line ends with a marker naming its pipeline
+pub fn comment_body(existing: Option<&str>, pipeline: &str, line: &str) -> String {
+    let pipeline_marker = format!("<!-- pipeline: {pipeline} -->");
+    let new_line = format!("- {line} {pipeline_marker}");
+
+    let mut lines: Vec<String> = existing
+        .map(|body| {
+            body.lines()
+                .filter(|l| l.starts_with("- ") && l.ends_with("-->"))
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    match lines.iter_mut().find(|l| l.ends_with(&pipeline_marker)) {
+        Some(l) => *l = new_line,
+        None => lines.push(new_line),
+    }
+
+    format!(
+        "{COMMENT_MARKER}\nBuilds of this pull request:\n\n{}",
+        lines.join("\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::model::{Label, PullRequest, User};
+
+    fn event(action: &str, author: &str, labels: &[&str], added: Option<&str>) -> PullRequestEvent {
+        let label = |name: &str| Label {
+            name: name.to_string(),
+        };
+
+        PullRequestEvent {
+            action: action.to_string(),
+            number: 12,
+            pull_request: PullRequest {
+                number: 12,
+                user: User {
+                    login: author.to_string(),
+                },
+                labels: labels.iter().map(|name| label(name)).collect(),
+                ..Default::default()
+            },
+            label: added.map(label),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn allows_authors_and_labels() {
+        let cfg = config::PullRequestConfig {
+            allowed_authors: Some(vec!["pajlada".to_string()]),
+            allowed_labels: Some(vec!["build-macos".to_string()]),
+            work_dir: String::new(),
+            release_tag: Some("pr-artifacts".to_string()),
+            artifact_dir: None,
+            public_url: None,
+        };
+
+        assert!(is_allowed(&cfg, &event("opened", "pajlada", &[], None)));
+        assert!(is_allowed(
+            &cfg,
+            &event("synchronize", "someone", &["build-macos"], None)
+        ));
+        assert!(!is_allowed(
+            &cfg,
+            &event("opened", "someone", &["bug"], None)
+        ));
+        assert!(is_allowed(
+            &cfg,
+            &event("labeled", "someone", &["build-macos"], Some("build-macos"))
+        ));
+        // Labeling a pull request of an allowed author doesn't rebuild it
+        assert!(!is_allowed(
+            &cfg,
+            &event("labeled", "pajlada", &["bug"], Some("bug"))
+        ));
+    }
+
+    #[test]
+    fn updates_comment_lines() {
+        let body = comment_body(None, "qt6", "**qt6**: build #1 failed at abc1234");
+        assert_eq!(
+            body,
+            "<!-- artifact-builder -->\nBuilds of this pull request:\n\n\
+             - **qt6**: build #1 failed at abc1234 <!-- pipeline: qt6 -->"
+        );
+
+        let body = comment_body(
+            Some(&body),
+            "qt5",
+            "**qt5**: [a.dmg](url) built from abc1234",
+        );
+        let body = comment_body(
+            Some(&body),
+            "qt6",
+            "**qt6**: [b.dmg](url) built from def5678",
+        );
+        assert_eq!(
+            body,
+            "<!-- artifact-builder -->\nBuilds of this pull request:\n\n\
+             - **qt6**: [b.dmg](url) built from def5678 <!-- pipeline: qt6 -->\n\
+             - **qt5**: [a.dmg](url) built from abc1234 <!-- pipeline: qt5 -->"
+        );
+    }
+
+    #[tokio::test]
+    async fn forgets_closed_pull_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            format!(
+                r#"
+[web]
+bind = ["127.0.0.1:0"]
+
+[github]
+token = "test-token"
+api_base_url = "http://127.0.0.1:1"
+verify_signature = false
+
+[build]
+dmg_output_path = "chatterino.dmg"
+state_dir = "{dir}/state"
+
+[[repositories]]
+owner = "owner"
+name = "repo"
+repo_dir = "{dir}/clone"
+branches = [{{ name = "master", release_tag = "nightly-build" }}]
+
+[repositories.pull_requests]
+allowed_authors = ["pajlada"]
+work_dir = "{dir}/prs"
+release_tag = "pr-artifacts"
+
+[[repositories.configs]]
+name = "qt6"
+cmake_args = []
+package_envs = []
+build_dir = "build"
+asset_name = "Chatterino.dmg"
+"#,
+                dir = dir.path().display(),
+            ),
+        )
+        .unwrap();
+
+        let cfg = config::read(path.to_str().unwrap()).unwrap();
+        let repository = &cfg.repositories[0];
+        let client = GithubClient::new(&cfg.github, &repository.owner, &repository.name).unwrap();
+        let state = Arc::new(State::open(&dir.path().join("state")).unwrap());
+        let pull_requests = PullRequests::new(&cfg, repository, client, state.clone()).unwrap();
+
+        let keys = [
+            "owner/repo/#12/qt6",
+            "owner/repo/#1/qt6",
+            "owner/repo/master/qt6",
+        ];
+        for key in keys {
+            state
+                .build_numbers
+                .update(|numbers| numbers.insert(key.to_string(), 3))
+                .unwrap();
+            state
+                .pending_uploads
+                .update(|pending| pending.insert(key.to_string(), "abc".to_string()))
+                .unwrap();
+            state
+                .built_commits
+                .update(|built| built.insert(key.to_string(), "abc".to_string()))
+                .unwrap();
+        }
+        std::fs::create_dir_all(pull_requests.repo_dir(12).join("build")).unwrap();
+
+        pull_requests.close(12).await.unwrap();
+
+        assert!(!pull_requests.repo_dir(12).exists());
+        let mut remaining = state
+            .build_numbers
+            .read(|numbers| numbers.keys().cloned().collect::<Vec<_>>());
+        remaining.sort();
+        assert_eq!(
+            remaining,
+            vec!["owner/repo/#1/qt6", "owner/repo/master/qt6"]
+        );
+        assert_eq!(state.pending_uploads.read(|pending| pending.len()), 2);
+        assert!(state
+            .built_commits
+            .read(|built| !built.contains_key("owner/repo/#12/qt6")));
+
+        // Pull requests that were never built are closed as well
+        pull_requests.close(13).await.unwrap();
+    }
+}
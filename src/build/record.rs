@@ -0,0 +1,188 @@
+use std::{fmt, time::Duration};
+
+use super::{now, BuildError, Step};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildStatus {
+    Running,
+    Succeeded,
+    Failed,
+
+    // Nothing was built, e.g. because the push only changed files excluded by the path filters
+    Skipped,
+}
+
+/// How long a single command of a build took
+#[derive(Debug, Clone)]
+pub struct StepTiming {
+    pub step: Step,
+    pub command: String,
+    pub duration: Duration,
+}
+
+/// The outcome of a single run of a pipeline
+#[derive(Debug, Clone)]
+pub struct BuildRecord {
+    // Unique ID of this build
+    pub id: String,
+
+    // Sequential number of this build within its pipeline. Skipped builds don't get one
+    pub number: Option<u64>,
+
+    pub pipeline: String,
+
+    // The branch that was built, or the pull request as e.g. "#123"
+    pub branch: String,
+
+    // The commit hash that was built, unknown until the repo has been checked out
+    pub commit_sha: Option<String>,
+
+    // Unix timestamps (in seconds)
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+
+    pub status: BuildStatus,
+
+    // The commands that ran, in order
+    pub steps: Vec<StepTiming>,
+
+    // Size of the built artifact in bytes
+    pub artifact_size: Option<u64>,
+
+    // Where the artifact can be downloaded from once it's uploaded
+    pub artifact_url: Option<String>,
+
+    // ID of the webhook delivery that triggered this build
+    pub delivery_id: Option<String>,
+
+    // Why nothing was built
+    pub skip_reason: Option<String>,
+
+    // Why the build failed
+    pub error: Option<BuildError>,
+}
+
+impl BuildRecord {
+    pub fn new(id: String, pipeline: &str, branch: &str) -> Self {
+        Self {
+            id,
+            number: None,
+            pipeline: pipeline.to_string(),
+            branch: branch.to_string(),
+            commit_sha: None,
+            started_at: now(),
+            finished_at: None,
+            status: BuildStatus::Running,
+            steps: vec![],
+            artifact_size: None,
+            artifact_url: None,
+            delivery_id: None,
+            skip_reason: None,
+            error: None,
+        }
+    }
+
+    pub fn finish(&mut self, result: Result<(), &BuildError>) {
+        self.finished_at = Some(now());
+
+        match result {
+            Ok(()) if self.skip_reason.is_some() => {
+                self.status = BuildStatus::Skipped;
+            }
+            Ok(()) => {
+                self.status = BuildStatus::Succeeded;
+            }
+            Err(e) => {
+                self.status = BuildStatus::Failed;
+                self.error = Some(e.clone());
+            }
+        }
+    }
+}
+
+/// A short, human readable summary of the build, used when notifying about the outcome of a build
+impl fmt::Display for BuildRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.number {
+            Some(number) => write!(f, "Build #{number} of {} ({}", self.pipeline, self.branch)?,
+            None => write!(f, "Build of {} ({}", self.pipeline, self.branch)?,
+        }
+        if let Some(commit_sha) = &self.commit_sha {
+            write!(f, " @ {}", &commit_sha[..commit_sha.len().min(7)])?;
+        }
+        write!(f, ")")?;
+
+        let duration = self
+            .finished_at
+            .unwrap_or_else(now)
+            .saturating_sub(self.started_at);
+
+        match (self.status, &self.error) {
+            (BuildStatus::Running, _) => write!(f, " is running for {duration}s"),
+            (BuildStatus::Succeeded, _) => write!(f, " succeeded in {duration}s"),
+            (BuildStatus::Skipped, _) => write!(
+                f,
+                " was skipped: {}",
+                self.skip_reason.as_deref().unwrap_or_default()
+            ),
+            (BuildStatus::Failed, Some(e)) => write!(f, " failed after {duration}s: {e}"),
+            (BuildStatus::Failed, None) => write!(f, " failed after {duration}s"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::{ErrorKind, Stage};
+
+    fn record() -> BuildRecord {
+        let mut record = BuildRecord::new("id".to_string(), "qt6", "#123");
+        record.commit_sha = Some("0123456789abcdef".to_string());
+        record
+    }
+
+    fn finished(mut record: BuildRecord, result: Result<(), &BuildError>) -> String {
+        record.finish(result);
+        record.started_at = 100;
+        record.finished_at = Some(142);
+        record.to_string()
+    }
+
+    #[test]
+    fn describes_builds() {
+        let mut running = record();
+        running.commit_sha = None;
+        assert!(running
+            .to_string()
+            .starts_with("Build of qt6 (#123) is running for "));
+
+        let mut succeeded = record();
+        succeeded.number = Some(7);
+        assert_eq!(
+            finished(succeeded, Ok(())),
+            "Build #7 of qt6 (#123 @ 0123456) succeeded in 42s"
+        );
+
+        let mut skipped = record();
+        skipped.skip_reason = Some("only docs changed".to_string());
+        assert_eq!(
+            finished(skipped, Ok(())),
+            "Build of qt6 (#123 @ 0123456) was skipped: only docs changed"
+        );
+
+        let error = BuildError::other(
+            Stage::Checkout,
+            Duration::from_millis(250),
+            &anyhow::anyhow!("remote not found").context("Cloning & checking out repo"),
+        );
+        assert_eq!(
+            error.kind,
+            ErrorKind::Other("Cloning & checking out repo: remote not found".to_string())
+        );
+        assert_eq!(
+            finished(record(), Err(&error)),
+            "Build of qt6 (#123 @ 0123456) failed after 42s: checkout stage failed: Cloning & checking out repo: remote not found after 0.2s"
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+#[allow(unused)]
+use tracing::log::*;
+
+use super::{branch_rule::BranchRule, Destination, Pipeline, Pipelines, PullRequests, State};
+use crate::config;
+use crate::github::{self, GithubClient};
+
+/// The pipelines building the branches & pull requests of a single repository
+pub struct Repository {
+    // owner/name, as written in the config
+    pub full_name: String,
+
+    pub github_client: GithubClient,
+
+    // The pipelines of the branches listed in the config
+    branches: Pipelines,
+
+    // Branches matching one of these are built as well
+    branch_rules: Vec<BranchRule>,
+
+    // The pipelines of branches matching a rule, created the first time the branch is built
+    matched_branches: Mutex<Pipelines>,
+
+    // None if pull request builds aren't configured
+    pub pull_requests: Option<PullRequests>,
+
+    // How often the branches are polled for new commits, None if they aren't
+    pub poll_interval: Option<Duration>,
+
+    // Used to create pipelines
+    cfg: config::RepositoryConfig,
+    build_cfg: config::BuildConfig,
+    web_base_url: String,
+    state: Arc<State>,
+}
+
+impl Repository {
+    pub fn new(
+        cfg: &config::Config,
+        repository: &config::RepositoryConfig,
+        github_client: GithubClient,
+        state: Arc<State>,
+    ) -> anyhow::Result<Self> {
+        let branch_rules = repository
+            .branch_patterns
+            .iter()
+            .flatten()
+            .map(|pattern| BranchRule::new(pattern.clone()))
+            .collect::<anyhow::Result<_>>()?;
+
+        let mut this = Self {
+            full_name: repository.full_name(),
+            pull_requests: PullRequests::new(cfg, repository, github_client.clone(), state.clone()),
+            github_client,
+            branches: Pipelines::new(),
+            branch_rules,
+            matched_branches: Mutex::new(Pipelines::new()),
+            poll_interval: repository.poll_interval_seconds.map(Duration::from_secs),
+            cfg: repository.clone(),
+            build_cfg: cfg.build.clone(),
+            web_base_url: cfg
+                .github
+                .web_base_url
+                .clone()
+                .unwrap_or_else(|| github::client::DEFAULT_WEB_BASE_URL.to_string()),
+            state,
+        };
+
+        this.branches = repository
+            .branches
+            .iter()
+            .map(|branch| {
+                (
+                    branch.name.clone(),
+                    this.create_pipelines(branch, |asset_name| asset_name.to_string()),
+                )
+            })
+            .collect();
+
+        for schedule in repository.schedules.iter().flatten() {
+            if this.pipelines(&schedule.branch).is_none() {
+                return Err(anyhow::anyhow!(
+                    "Branch {} of {} is scheduled but not built, it must be listed in branches or match one of branch_patterns",
+                    schedule.branch,
+                    this.full_name
+                ));
+            }
+        }
+
+        Ok(this)
+    }
+
+    fn create_pipelines(
+        &self,
+        branch: &config::BranchAndRelease,
+        asset_name: impl Fn(&str) -> String,
+    ) -> Vec<Arc<Pipeline>> {
+        self.cfg
+            .configs
+            .iter()
+            .map(|c| {
+                let mut c = c.clone();
+                // The pipeline keeps the name of the config's asset
+                c.name = c.name.or_else(|| Some(c.asset_name.clone()));
+                c.asset_name = asset_name(&c.asset_name);
+
+                Arc::new(Pipeline::new(
+                    self.github_client.clone(),
+                    self.state.clone(),
+                    &self.cfg.repo_dir,
+                    &self.build_cfg.dmg_output_path,
+                    &self.web_base_url,
+                    self.cfg.owner.clone(),
+                    self.cfg.name.clone(),
+                    branch,
+                    Destination::Release(branch.release()),
+                    &self.build_cfg.default_config,
+                    c,
+                ))
+            })
+            .collect()
+    }
+
+    /// Returns the pipelines building the branch, or None if the branch isn't built.
+    /// Branches listed in the config take precedence over the first rule matching the branch
+    pub fn pipelines(&self, branch: &str) -> Option<Vec<Arc<Pipeline>>> {
+        if let Some(pipelines) = self.branches.get(branch) {
+            return Some(pipelines.clone());
+        }
+
+        let mut matched_branches = self.matched_branches.lock().unwrap();
+        if let Some(pipelines) = matched_branches.get(branch) {
+            return Some(pipelines.clone());
+        }
+
+        let rule = self.branch_rules.iter().find(|rule| rule.matches(branch))?;
+        info!("Creating the pipelines of {branch} of {}", self.full_name);
+
+        let pipelines = self.create_pipelines(&rule.branch(branch), |asset_name| {
+            rule.asset_name(branch, asset_name)
+        });
+        matched_branches.insert(branch.to_string(), pipelines.clone());
+
+        Some(pipelines)
+    }
+
+    /// Drops the pipelines of a deleted branch that matched a rule, they're created again if the
+    /// branch is pushed anew
+    pub fn forget_branch(&self, branch: &str) {
+        if self
+            .matched_branches
+            .lock()
+            .unwrap()
+            .remove(branch)
+            .is_some()
+        {
+            info!("Dropping the pipelines of {branch} of {}", self.full_name);
+        }
+    }
+
+    /// Drops the pipelines of the branches matching a rule that no longer exist
+    pub fn forget_deleted_branches(&self, exists: impl Fn(&str) -> bool) {
+        self.matched_branches.lock().unwrap().retain(|branch, _| {
+            let exists = exists(branch);
+            if !exists {
+                info!("Dropping the pipelines of {branch} of {}", self.full_name);
+            }
+            exists
+        });
+    }
+
+    /// Returns the URL the repo is cloned from
+    pub fn clone_url(&self) -> String {
+        format!(
+            "{}/{}/{}",
+            self.web_base_url.trim_end_matches('/'),
+            self.cfg.owner,
+            self.cfg.name
+        )
+    }
+
+    /// Returns the directory the branches are cloned & built in
+    pub fn repo_dir(&self) -> &std::path::Path {
+        std::path::Path::new(&self.cfg.repo_dir)
+    }
+
+    /// Returns the scheduled builds of the repository's branches
+    pub fn schedules(&self) -> &[config::ScheduleConfig] {
+        self.cfg.schedules.as_deref().unwrap_or_default()
+    }
+
+    /// Returns the pipelines of the branches listed in the config
+    pub fn configured_pipelines(&self) -> impl Iterator<Item = &Arc<Pipeline>> {
+        self.branches.values().flatten()
+    }
+}
+
+/// All repositories the builder builds
+pub struct Repositories(Vec<Repository>);
+
+impl Repositories {
+    pub fn new(repositories: Vec<Repository>) -> Self {
+        Self(repositories)
+    }
+
+    /// Looks up a repository by its full name, ignoring case like GitHub does
+    pub fn get(&self, full_name: &str) -> Option<&Repository> {
+        self.0
+            .iter()
+            .find(|repository| repository.full_name.eq_ignore_ascii_case(full_name))
+    }
+
+    /// Returns the only repository, if exactly one is configured
+    pub fn single(&self) -> Option<&Repository> {
+        match self.0.as_slice() {
+            [repository] => Some(repository),
+            _ => None,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Repository> {
+        self.0.iter()
+    }
+}
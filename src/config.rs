@@ -1,6 +1,10 @@
+use std::collections::{HashMap, HashSet};
+
 use anyhow::{Context, Result};
 use serde::Deserialize;
 
+use crate::build::Step;
+
 use figment::{
     providers::{Format, Toml},
     Figment,
@@ -9,7 +13,68 @@ use figment::{
 #[derive(Debug, Deserialize, Clone)]
 pub struct BranchAndRelease {
     pub name: String,
-    pub release_id: i64,
+
+    // The release assets are uploaded to, either by its ID or by its tag name
+    pub release_id: Option<i64>,
+    pub release_tag: Option<String>,
+
+    // Whether a release that is created because its tag doesn't exist is marked as a prerelease
+    pub prerelease: Option<bool>,
+
+    // Whether the release's tag is moved to the built commit after a successful upload,
+    // for rolling releases like a nightly build. The release's name & body are updated too
+    pub move_tag: Option<bool>,
+
+    // Whether the release's name & body are updated after a successful upload,
+    // with release notes listing the pushed commits
+    pub release_notes: Option<bool>,
+
+    // Templates for the release's name & body when the release is updated. Available placeholders:
+    // {tag}, {branch}, {commit}, {short_sha}, {build_number}, {date}, {changelog}
+    pub release_name: Option<String>,
+    pub release_body: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct BranchPattern {
+    // Branches whose name matches the glob (e.g. "release/*") or the regex are built.
+    // In globs, * doesn't match a /, ** does
+    pub glob: Option<String>,
+    pub regex: Option<String>,
+
+    // Templates for the tag of the release assets are uploaded to, and for the asset names.
+    // Available placeholders: {branch} & {branch_slug}, and {asset_name} for asset names
+    pub release_tag: String,
+    pub asset_name: Option<String>,
+
+    // Same as for branches
+    pub prerelease: Option<bool>,
+    pub move_tag: Option<bool>,
+    pub release_notes: Option<bool>,
+    pub release_name: Option<String>,
+    pub release_body: Option<String>,
+}
+
+/// How a branch refers to its release
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReleaseRef {
+    Id(i64),
+
+    // Looked up by its tag name, and created if it doesn't exist
+    Tag { tag: String, prerelease: bool },
+}
+
+impl BranchAndRelease {
+    pub fn release(&self) -> ReleaseRef {
+        match (&self.release_tag, self.release_id) {
+            (Some(tag), _) => ReleaseRef::Tag {
+                tag: tag.clone(),
+                prerelease: self.prerelease.unwrap_or(false),
+            },
+            (None, Some(id)) => ReleaseRef::Id(id),
+            (None, None) => unreachable!("validated when reading the config"),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -18,35 +83,235 @@ pub struct EnvironmentVariable {
     pub value: String,
 }
 
+/// A command run as part of a build step
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Command {
+    // A command line that is run through `sh -c`
+    Shell(String),
+
+    // A program & its arguments that are run directly, without a shell
+    Argv(Vec<String>),
+}
+
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Command::Shell(command) => f.write_str(command),
+            Command::Argv(argv) => f.write_str(&argv.join(" ")),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct DefaultBuild {
+    // CMake generator, e.g. "Ninja" or "Unix Makefiles". Uses cmake's default if unset
+    pub generator: Option<String>,
+    // Number of parallel build jobs. Defaults to the number of CPUs
+    pub parallelism: Option<usize>,
     pub cmake_args: Vec<String>,
     pub package_envs: Vec<EnvironmentVariable>,
+    pub step_envs: Option<HashMap<Step, Vec<EnvironmentVariable>>>,
+    // Report builds as GitHub Check Runs. Requires authenticating as a GitHub App
+    pub check_runs: Option<bool>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Build {
-    pub pre_cmake_commands: Option<Vec<String>>,
+    // Name of the pipeline, defaults to the asset name
+    pub name: Option<String>,
+
+    pub pre_cmake_commands: Option<Vec<Command>>,
+    // Overrides the generator & parallelism from the default config
+    pub generator: Option<String>,
+    pub parallelism: Option<usize>,
     pub cmake_args: Vec<String>,
-    pub pre_package_commands: Option<Vec<String>>,
+    pub pre_package_commands: Option<Vec<Command>>,
     pub package_envs: Vec<EnvironmentVariable>,
-    pub pre_dmg_commands: Option<Vec<String>>,
+    pub pre_dmg_commands: Option<Vec<Command>>,
+    // Extra environment variables for the commands of a single step
+    pub step_envs: Option<HashMap<Step, Vec<EnvironmentVariable>>>,
     pub build_dir: String,
     pub asset_name: String,
+
+    // Keep the build directory between builds and only rerun cmake & make
+    pub incremental: Option<bool>,
+    // When building incrementally, do a full clean build if the last one is older than this
+    pub clean_build_interval_hours: Option<u64>,
+
+    // Overrides check_runs from the default config
+    pub check_runs: Option<bool>,
+
+    // A push is only built if it changed a file matching one of include_paths (if set)
+    // that doesn't match any of exclude_paths. In globs, * doesn't match a /, ** does
+    pub include_paths: Option<Vec<String>>,
+    pub exclude_paths: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct GithubAppConfig {
+    pub app_id: u64,
+    pub installation_id: u64,
+
+    // Path to the private key (.pem) generated for the app
+    pub private_key_path: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebhookSecret {
+    // Identifies the secret in logs, e.g. when it was created
+    pub id: String,
+
+    pub secret: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PullRequestConfig {
+    // Pull requests are built if their author or one of their labels is on these lists
+    pub allowed_authors: Option<Vec<String>>,
+    pub allowed_labels: Option<Vec<String>>,
+
+    // Each pull request is cloned & built in a directory of its own inside this one
+    pub work_dir: String,
+
+    // Artifacts are uploaded to the release with this tag, which is created if it doesn't exist
+    pub release_tag: Option<String>,
+
+    // Or they're kept in this directory and served by the builder under /artifacts
+    pub artifact_dir: Option<String>,
+
+    // URL the builder can be reached at from the outside, used to link to the artifact_dir
+    pub public_url: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct GithubConfig {
-    pub token: String,
+    // Personal access token used to authenticate with GitHub
+    pub token: Option<String>,
+
+    // Authenticate as a GitHub App installation instead of with a personal access token
+    pub app: Option<GithubAppConfig>,
+
+    // Base URLs of the GitHub REST API & the release asset upload server.
+    // Defaults to github.com, set these to use GitHub Enterprise Server or a local mock server
+    pub api_base_url: Option<String>,
+    pub upload_base_url: Option<String>,
+    // Base URL repos are cloned from, defaults to https://github.com
+    pub web_base_url: Option<String>,
+
+    // How often a failed GitHub request is retried, with exponential backoff
+    pub max_retries: Option<u32>,
 
     pub verify_signature: bool,
 
-    pub secret: String,
+    // The webhook secret, accepted for deliveries of all repositories. Deliveries signed with any
+    // of `secrets` are accepted as well, so a secret can be rotated without rejecting deliveries
+    pub secret: Option<String>,
+    pub secrets: Option<Vec<WebhookSecret>>,
+
+    // Accept deliveries only signed with SHA-1 (x-hub-signature), e.g. from old GitHub Enterprise
+    // Server versions. SHA-256 signatures are still preferred if present
+    pub legacy_signature: Option<bool>,
+
+    // A single repository, from before multiple repositories were supported.
+    // Converted into the only entry of `repositories` when reading the config
+    pub repo_owner: Option<String>,
+    pub repo_name: Option<String>,
+    pub branches: Option<Vec<BranchAndRelease>>,
+    pub pull_requests: Option<PullRequestConfig>,
+}
+
+impl GithubConfig {
+    /// Returns the webhook secrets accepted for all repositories. `secret` has the ID "default"
+    pub fn webhook_secrets(&self) -> Vec<WebhookSecret> {
+        webhook_secrets("default", &self.secret, &self.secrets)
+    }
+
+    /// Returns owner/name of the repository configured in this section, from before multiple
+    /// repositories were supported
+    pub fn legacy_full_name(&self) -> Option<String> {
+        match (&self.repo_owner, &self.repo_name) {
+            (Some(owner), Some(name)) => Some(format!("{owner}/{name}")),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ScheduleConfig {
+    // When to build, in the builder's local time. Either a crontab expression (minute, hour, day of
+    // month, month & day of week) or one with seconds first & an optional year after
+    pub cron: String,
+
+    // The branch to build, which must be built by the repository
+    pub branch: String,
+
+    // Wipe the build directory first, e.g. to catch toolchain changes
+    pub clean: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RepositoryConfig {
+    // https://github.com/{owner}/{name}
+    pub owner: String,
+    pub name: String,
 
-    pub repo_owner: String,
-    pub repo_name: String,
+    // Directory the repo is cloned & built in, must not be shared with another repository
+    pub repo_dir: String,
+
+    // Webhook secrets only accepted for deliveries of this repository,
+    // in addition to the ones in the github section
+    pub secret: Option<String>,
+    pub secrets: Option<Vec<WebhookSecret>>,
 
     pub branches: Vec<BranchAndRelease>,
+
+    // Branches that aren't listed in `branches` are built if they match one of these.
+    // Their pipelines are created the first time the branch is built
+    pub branch_patterns: Option<Vec<BranchPattern>>,
+
+    // Each branch is built with all of these configs
+    pub configs: Vec<Build>,
+
+    // Build pull requests & link to their artifacts in a comment
+    pub pull_requests: Option<PullRequestConfig>,
+
+    // Check the repo's branches for new commits this often, for builders that can't receive webhooks
+    pub poll_interval_seconds: Option<u64>,
+
+    // Installation of the GitHub App in github.app used for this repository, if it differs from
+    // github.app.installation_id, e.g. for a fork owned by another account
+    pub installation_id: Option<u64>,
+
+    // Build branches at fixed times, even if nothing was pushed
+    pub schedules: Option<Vec<ScheduleConfig>>,
+}
+
+impl RepositoryConfig {
+    pub fn full_name(&self) -> String {
+        format!("{}/{}", self.owner, self.name)
+    }
+
+    /// Returns the webhook secrets only accepted for this repository. `secret` has the
+    /// repository's full name as its ID
+    pub fn webhook_secrets(&self) -> Vec<WebhookSecret> {
+        webhook_secrets(&self.full_name(), &self.secret, &self.secrets)
+    }
+}
+
+fn webhook_secrets(
+    id: &str,
+    secret: &Option<String>,
+    secrets: &Option<Vec<WebhookSecret>>,
+) -> Vec<WebhookSecret> {
+    secret
+        .iter()
+        .map(|secret| WebhookSecret {
+            id: id.to_string(),
+            secret: secret.clone(),
+        })
+        .chain(secrets.iter().flatten().cloned())
+        .collect()
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -54,15 +319,26 @@ pub struct WebConfig {
     pub base_url: String,
 
     pub bind: Vec<String>,
+
+    // Token required to trigger builds manually through the /build endpoint
+    // If unset, manual triggers are disabled
+    pub trigger_token: Option<String>,
+
+    // Largest accepted request body in bytes, defaults to 25 MiB
+    pub max_body_size: Option<usize>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct BuildConfig {
-    pub repo_dir: String,
     pub dmg_output_path: String,
 
+    // Directory where the builder keeps state that must survive restarts (e.g. build numbers)
+    pub state_dir: String,
+
     pub default_config: DefaultBuild,
 
+    // Clone directory & build configs of the single repository in the github section
+    pub repo_dir: Option<String>,
     pub configs: Vec<Build>,
 }
 
@@ -73,14 +349,20 @@ pub struct Config {
     pub github: GithubConfig,
 
     pub build: BuildConfig,
+
+    // The repositories that are built. If empty, the repository from the github section is built
+    pub repositories: Vec<RepositoryConfig>,
 }
 
 pub fn read(path: &str) -> Result<Config> {
     let default_config = r#"
+repositories = []
+
 [web]
 base_url = "/"
 
 [build]
+state_dir = "state"
 default_config = { cmake_args = [], package_envs = [] }
 configs = []
 
@@ -88,7 +370,7 @@ configs = []
 verify_signature = true
 "#;
 
-    let config: Config = Figment::new()
+    let mut config: Config = Figment::new()
         .merge(Toml::string(default_config))
         .merge(Toml::file(path))
         .extract()
@@ -98,9 +380,329 @@ verify_signature = true
         return Err(anyhow::anyhow!("Must include at least one bind interface"));
     }
 
-    if config.build.configs.is_empty() {
-        return Err(anyhow::anyhow!("Must include at least one build config"));
+    if config.github.token.is_some() == config.github.app.is_some() {
+        return Err(anyhow::anyhow!(
+            "Must include exactly one of github.token or github.app"
+        ));
+    }
+
+    if config.repositories.is_empty() {
+        let repository = legacy_repository(&config)?;
+        config.repositories.push(repository);
+    } else if config.github.repo_owner.is_some()
+        || config.github.repo_name.is_some()
+        || config.github.branches.is_some()
+        || config.github.pull_requests.is_some()
+        || config.build.repo_dir.is_some()
+        || !config.build.configs.is_empty()
+    {
+        return Err(anyhow::anyhow!(
+            "The repository in the github section can't be combined with [[repositories]]"
+        ));
+    }
+
+    // Jobs are keyed by the directory they clone into, so no two repositories can share one
+    let mut dirs = HashSet::new();
+    let mut names = HashSet::new();
+
+    for repository in &config.repositories {
+        let full_name = repository.full_name();
+
+        if !names.insert(full_name.to_lowercase()) {
+            return Err(anyhow::anyhow!(
+                "Repository {full_name} is configured more than once"
+            ));
+        }
+
+        if repository.configs.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Repository {full_name} must include at least one build config"
+            ));
+        }
+
+        // The Checks API only accepts the tokens of GitHub App installations
+        if config.github.app.is_none()
+            && repository.configs.iter().any(|c| {
+                c.check_runs
+                    .or(config.build.default_config.check_runs)
+                    .unwrap_or(false)
+            })
+        {
+            return Err(anyhow::anyhow!(
+                "Check runs of {full_name} require authenticating with github.app instead of a token"
+            ));
+        }
+
+        if config.github.verify_signature
+            && config.github.webhook_secrets().is_empty()
+            && repository.webhook_secrets().is_empty()
+        {
+            return Err(anyhow::anyhow!(
+                "Repository {full_name} must have a secret, or github.secret must be set, to verify signatures"
+            ));
+        }
+
+        for branch in &repository.branches {
+            if branch.release_id.is_some() == branch.release_tag.is_some() {
+                return Err(anyhow::anyhow!(
+                    "Branch {} of {full_name} must have exactly one of release_id or release_tag",
+                    branch.name
+                ));
+            }
+        }
+
+        if repository.installation_id.is_some() && config.github.app.is_none() {
+            return Err(anyhow::anyhow!(
+                "installation_id of {full_name} requires authenticating as a GitHub App in github.app"
+            ));
+        }
+
+        if repository.poll_interval_seconds == Some(0) {
+            return Err(anyhow::anyhow!(
+                "poll_interval_seconds of {full_name} must be greater than 0"
+            ));
+        }
+
+        for schedule in repository.schedules.iter().flatten() {
+            crate::schedule::parse(&schedule.cron)
+                .context(format!("Schedule of {} of {full_name}", schedule.branch))?;
+        }
+
+        for build in &repository.configs {
+            crate::build::PathFilter::new(
+                build.include_paths.as_deref(),
+                build.exclude_paths.as_deref(),
+            )
+            .context(format!(
+                "Path filters of {} of {full_name}",
+                build.name.as_ref().unwrap_or(&build.asset_name)
+            ))?;
+        }
+
+        for pattern in repository.branch_patterns.iter().flatten() {
+            if pattern.glob.is_some() == pattern.regex.is_some() {
+                return Err(anyhow::anyhow!(
+                    "Branch patterns of {full_name} must have exactly one of glob or regex"
+                ));
+            }
+
+            // Otherwise the assets of every matching branch are uploaded under the same name to
+            // the same release, overwriting each other
+            let has_branch = |template: &str| {
+                template.contains("{branch}") || template.contains("{branch_slug}")
+            };
+            if !has_branch(&pattern.release_tag)
+                && !pattern.asset_name.as_deref().is_some_and(has_branch)
+            {
+                return Err(anyhow::anyhow!(
+                    "Branch pattern {} of {full_name} shares the release {} between branches, its asset_name must contain {{branch}} or {{branch_slug}}",
+                    pattern.glob.as_ref().or(pattern.regex.as_ref()).unwrap(),
+                    pattern.release_tag
+                ));
+            }
+        }
+
+        let mut repository_dirs = vec![&repository.repo_dir];
+
+        if let Some(pull_requests) = &repository.pull_requests {
+            if pull_requests.release_tag.is_some() == pull_requests.artifact_dir.is_some() {
+                return Err(anyhow::anyhow!(
+                    "pull_requests of {full_name} must have exactly one of release_tag or artifact_dir"
+                ));
+            }
+
+            if pull_requests.artifact_dir.is_some() && pull_requests.public_url.is_none() {
+                return Err(anyhow::anyhow!(
+                    "pull_requests.public_url of {full_name} is required to link to the artifact_dir"
+                ));
+            }
+
+            repository_dirs.push(&pull_requests.work_dir);
+            repository_dirs.extend(&pull_requests.artifact_dir);
+        }
+
+        for dir in repository_dirs {
+            if !dirs.insert(std::path::absolute(dir).unwrap_or_else(|_| dir.into())) {
+                return Err(anyhow::anyhow!(
+                    "Directory {dir} of {full_name} is used more than once"
+                ));
+            }
+        }
     }
 
     Ok(config)
 }
+
+/// Converts the repository configured in the github & build sections
+fn legacy_repository(config: &Config) -> Result<RepositoryConfig> {
+    let (Some(owner), Some(name)) = (&config.github.repo_owner, &config.github.repo_name) else {
+        return Err(anyhow::anyhow!(
+            "Must include at least one repository, or github.repo_owner & github.repo_name"
+        ));
+    };
+
+    Ok(RepositoryConfig {
+        owner: owner.clone(),
+        name: name.clone(),
+        repo_dir: config
+            .build
+            .repo_dir
+            .clone()
+            .context("Must include build.repo_dir")?,
+        // github.secret is accepted for all repositories
+        secret: None,
+        secrets: None,
+        branches: config.github.branches.clone().unwrap_or_default(),
+        branch_patterns: None,
+        configs: config.build.configs.clone(),
+        pull_requests: config.github.pull_requests.clone(),
+        poll_interval_seconds: None,
+        schedules: None,
+        installation_id: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Commands {
+        commands: Vec<Command>,
+    }
+
+    #[test]
+    fn deserializes_commands() {
+        let commands: Commands = Figment::new()
+            .merge(Toml::string(
+                r#"commands = ["brew upgrade && brew cleanup", ["conan", "install", "..", "-b", "missing"]]"#,
+            ))
+            .extract()
+            .unwrap();
+
+        assert_eq!(
+            commands.commands,
+            vec![
+                Command::Shell("brew upgrade && brew cleanup".to_string()),
+                Command::Argv(
+                    ["conan", "install", "..", "-b", "missing"]
+                        .map(String::from)
+                        .to_vec()
+                ),
+            ]
+        );
+    }
+
+    fn read_with_branch_pattern(pattern: &str) -> Result<Config> {
+        read_with(&format!("branch_patterns = [{pattern}]"), "")
+    }
+
+    /// Reads a config with a single repository, with the given lines added to the repository &
+    /// to its build config
+    fn read_with(repository: &str, build_config: &str) -> Result<Config> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            format!(
+                r#"
+[web]
+bind = ["127.0.0.1:0"]
+
+[github]
+token = "test-token"
+verify_signature = false
+
+[build]
+dmg_output_path = "chatterino.dmg"
+
+[[repositories]]
+owner = "owner"
+name = "repo"
+repo_dir = "clone"
+branches = []
+{repository}
+
+[[repositories.configs]]
+cmake_args = []
+package_envs = []
+build_dir = "build"
+asset_name = "Chatterino.dmg"
+{build_config}
+"#
+            ),
+        )
+        .unwrap();
+
+        read(path.to_str().unwrap())
+    }
+
+    #[test]
+    fn names_the_legacy_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            r#"
+[web]
+bind = ["127.0.0.1:0"]
+
+[github]
+token = "test-token"
+verify_signature = false
+repo_owner = "owner"
+repo_name = "repo"
+branches = [{ name = "master", release_tag = "nightly-build" }]
+
+[build]
+dmg_output_path = "chatterino.dmg"
+repo_dir = "clone"
+
+[[build.configs]]
+cmake_args = []
+package_envs = []
+build_dir = "build"
+asset_name = "Chatterino.dmg"
+"#,
+        )
+        .unwrap();
+
+        let cfg = read(path.to_str().unwrap()).unwrap();
+        assert_eq!(cfg.github.legacy_full_name().as_deref(), Some("owner/repo"));
+        assert_eq!(cfg.repositories[0].full_name(), "owner/repo");
+
+        // A single repository of the repositories section isn't a legacy one
+        let cfg = read_with_branch_pattern(r#"{ glob = "*", release_tag = "{branch}" }"#).unwrap();
+        assert_eq!(cfg.github.legacy_full_name(), None);
+    }
+
+    #[test]
+    fn rejects_check_runs_with_tokens() {
+        assert!(read_with("", "check_runs = false").is_ok());
+
+        let err = read_with("", "check_runs = true").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("require authenticating with github.app"));
+    }
+
+    #[test]
+    fn rejects_branch_patterns_sharing_asset_names() {
+        assert!(read_with_branch_pattern(
+            r#"{ glob = "release/*", release_tag = "{branch_slug}-nightly" }"#
+        )
+        .is_ok());
+        assert!(read_with_branch_pattern(
+            r#"{ glob = "release/*", release_tag = "nightlies", asset_name = "{branch}-{asset_name}" }"#
+        )
+        .is_ok());
+
+        let err = read_with_branch_pattern(r#"{ glob = "release/*", release_tag = "nightlies" }"#)
+            .unwrap_err();
+        assert!(err.to_string().contains("shares the release nightlies"));
+        assert!(read_with_branch_pattern(
+            r#"{ glob = "release/*", release_tag = "nightlies", asset_name = "latest-{asset_name}" }"#
+        )
+        .is_err());
+    }
+}
@@ -1,94 +1,299 @@
 // Most code based off of https://github.com/rust-lang/git2-rs/blob/master/examples/pull.rs
 
-use anyhow::anyhow;
-use git2::{BranchType, Repository};
+use std::path::Path;
+
+use git2::Repository;
 
 #[allow(unused)]
 use tracing::log::*;
 
-pub fn fetch<'a>(
-    repo: &'a git2::Repository,
-    remote: &'a mut git2::Remote,
-) -> Result<git2::AnnotatedCommit<'a>, git2::Error> {
+pub fn clone(url: &str, path: &Path, branch: &str) -> Result<Repository, git2::Error> {
+    let repo = git2::build::RepoBuilder::new()
+        .branch(branch)
+        .clone(url, path)?;
+
+    update_submodules(&repo)?;
+
+    Ok(repo)
+}
+
+fn update_submodules(repo: &Repository) -> Result<(), git2::Error> {
+    for mut submodule in repo.submodules()? {
+        submodule.update(true, None)?;
+        update_submodules(&submodule.open()?)?;
+    }
+
+    Ok(())
+}
+
+/// Fetches the branch from origin and checks it out, discarding local changes & commits.
+/// The local branch is created if it doesn't exist yet, e.g. for a branch that wasn't built before,
+/// and reset to the fetched commit otherwise, so force pushes are built as well
+pub fn checkout_branch(repo: &Repository, branch: &str) -> Result<(), git2::Error> {
+    let remote_ref = format!("refs/remotes/origin/{branch}");
+
     let mut fo = git2::FetchOptions::new();
     fo.download_tags(git2::AutotagOption::All);
 
-    remote.fetch(&[] as &[&str], Some(&mut fo), None)?;
+    let mut remote = repo.find_remote("origin")?;
+    remote.fetch(
+        &[format!("+refs/heads/{branch}:{remote_ref}")],
+        Some(&mut fo),
+        None,
+    )?;
+
+    let commit = repo.find_reference(&remote_ref)?.peel_to_commit()?;
+    info!("Checking out {branch} at {}", commit.id());
+
+    repo.checkout_tree(
+        commit.as_object(),
+        Some(git2::build::CheckoutBuilder::default().force()),
+    )?;
+
+    // The branch can't be reset while it's checked out
+    repo.set_head_detached(commit.id())?;
+    repo.branch(branch, &commit, true)?;
+    repo.set_head(&format!("refs/heads/{branch}"))?;
+
+    update_submodules(repo)
+}
+
+/// Lists the branches of the remote repo & the commit hashes they point to, without cloning it
+pub fn ls_remote(url: &str) -> Result<Vec<(String, String)>, git2::Error> {
+    let mut remote = git2::Remote::create_detached(url)?;
+    remote.connect(git2::Direction::Fetch)?;
+
+    let branches = remote
+        .list()?
+        .iter()
+        .filter_map(|head| {
+            let branch = head.name().strip_prefix("refs/heads/")?;
+            Some((branch.to_string(), head.oid().to_string()))
+        })
+        .collect();
+
+    Ok(branches)
+}
+
+/// Returns the commit hash HEAD currently points to
+pub fn head_commit(repo: &Repository) -> Result<String, git2::Error> {
+    Ok(repo.head()?.peel_to_commit()?.id().to_string())
+}
 
-    let local_branch = repo.find_branch("master", BranchType::Local)?;
-    let upstream_branch = local_branch.upstream()?;
-    let upstream_commit = upstream_branch.into_reference().peel_to_commit()?;
+/// A commit as it's listed in a changelog
+#[derive(Debug, Clone)]
+pub struct CommitSummary {
+    pub sha: String,
 
-    let upstream_annotated = repo.find_annotated_commit(upstream_commit.id())?;
+    // First line of the commit message
+    pub subject: String,
 
-    Ok(upstream_annotated)
+    pub author: String,
 }
 
-fn fast_forward(
+/// Returns the paths of the files that differ between the two commits, sorted.
+/// Renamed files are listed with their old & new path
+pub fn changed_files(repo: &Repository, from: &str, to: &str) -> Result<Vec<String>, git2::Error> {
+    let from = repo.find_commit(git2::Oid::from_str(from)?)?.tree()?;
+    let to = repo.find_commit(git2::Oid::from_str(to)?)?.tree()?;
+
+    let diff = repo.diff_tree_to_tree(Some(&from), Some(&to), None)?;
+
+    let mut files: Vec<String> = diff
+        .deltas()
+        .flat_map(|delta| [delta.old_file().path(), delta.new_file().path()])
+        .flatten()
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+    files.sort();
+    files.dedup();
+
+    Ok(files)
+}
+
+/// Returns the commits reachable from `to` but not from `from`, newest first
+pub fn commit_range(
     repo: &Repository,
-    lb: &mut git2::Reference,
-    rc: &git2::AnnotatedCommit,
-) -> Result<(), git2::Error> {
-    let name: String = match lb.name() {
-        Ok(s) => s.to_string(),
-        Err(_) => String::from_utf8_lossy(lb.name_bytes()).to_string(),
-    };
-    let msg = format!("Fast-Forward: Setting {} to id: {}", name, rc.id());
-    println!("{}", msg);
-    lb.set_target(rc.id(), &msg)?;
-    repo.set_head(&name)?;
-    repo.checkout_head(Some(
-        git2::build::CheckoutBuilder::default()
-            // For some reason the force is required to make the working directory actually get updated
-            // I suspect we should be adding some logic to handle dirty working directory states
-            // but this is just an example so maybe not.
-            .force(),
-    ))?;
-    Ok(())
+    from: &str,
+    to: &str,
+) -> Result<Vec<CommitSummary>, git2::Error> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::TIME)?;
+    revwalk.push(git2::Oid::from_str(to)?)?;
+    revwalk.hide(git2::Oid::from_str(from)?)?;
+
+    revwalk
+        .map(|oid| {
+            let commit = repo.find_commit(oid?)?;
+            let author = commit.author();
+
+            Ok(CommitSummary {
+                sha: commit.id().to_string(),
+                subject: String::from_utf8_lossy(commit.summary_bytes().unwrap_or_default())
+                    .into_owned(),
+                author: String::from_utf8_lossy(author.name_bytes()).into_owned(),
+            })
+        })
+        .collect()
 }
 
-pub fn merge<'a>(
-    repo: &'a Repository,
-    remote_branch: &str,
-    fetch_commit: git2::AnnotatedCommit<'a>,
-) -> anyhow::Result<()> {
-    // 1. do a merge analysis
-    let analysis = repo.merge_analysis(&[&fetch_commit])?;
-
-    // 2. Do the appropriate merge
-    if analysis.0.is_fast_forward() {
-        println!("Doing a fast forward");
-        // do a fast forward
-        let refname = format!("refs/heads/{}", remote_branch);
-        match repo.find_reference(&refname) {
-            Ok(mut r) => {
-                fast_forward(repo, &mut r, &fetch_commit)?;
-            }
-            Err(_) => {
-                // The branch doesn't exist so just set the reference to the
-                // commit directly. Usually this is because you are pulling
-                // into an empty repository.
-                repo.reference(
-                    &refname,
-                    fetch_commit.id(),
-                    true,
-                    &format!("Setting {} to {}", remote_branch, fetch_commit.id()),
-                )?;
-                repo.set_head(&refname)?;
-                repo.checkout_head(Some(
-                    git2::build::CheckoutBuilder::default()
-                        .allow_conflicts(true)
-                        .conflict_style_merge(true)
-                        .force(),
-                ))?;
+/// Fetches the head of the pull request from origin and checks it out as a detached HEAD
+pub fn checkout_pull_request(repo: &Repository, number: u64) -> Result<(), git2::Error> {
+    let refname = format!("refs/pull/{number}/head");
+
+    let mut remote = repo.find_remote("origin")?;
+    remote.fetch(&[format!("+{refname}:{refname}")], None, None)?;
+
+    let commit = repo.find_reference(&refname)?.peel_to_commit()?;
+    repo.checkout_tree(
+        commit.as_object(),
+        Some(git2::build::CheckoutBuilder::default().force()),
+    )?;
+    repo.set_head_detached(commit.id())?;
+
+    update_submodules(repo)
+}
+
+/// Helpers for tests against local repos
+#[cfg(test)]
+pub mod testing {
+    use std::path::Path;
+
+    use git2::Repository;
+
+    /// Creates a repo with master checked out, e.g. to clone from with a file:// URL
+    pub fn init(path: &Path) -> Repository {
+        let mut opts = git2::RepositoryInitOptions::new();
+        opts.initial_head("master");
+        Repository::init_opts(path, &opts).unwrap()
+    }
+
+    /// Writes the files (deleting the ones without contents) and commits all changes to HEAD,
+    /// returning the commit's hash
+    pub fn commit(repo: &Repository, files: &[(&str, Option<&str>)], message: &str) -> String {
+        let workdir = repo.workdir().unwrap();
+        for (path, contents) in files {
+            let path = workdir.join(path);
+            match contents {
+                Some(contents) => {
+                    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                    std::fs::write(path, contents).unwrap();
+                }
+                None => std::fs::remove_file(path).unwrap(),
             }
-        };
-    } else if analysis.0.is_normal() {
-        return Err(anyhow!(
-            "Unable to merge upstream changes to local, no appropriate merge strategy found"
-        ));
-    } else {
-        println!("Nothing to do...");
+        }
+
+        let mut index = repo.index().unwrap();
+        index
+            .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+            .unwrap();
+        index.update_all(["*"], None).unwrap();
+        index.write().unwrap();
+
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("Tester", "tester@example.com").unwrap();
+        let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &parent.iter().collect::<Vec<_>>(),
+        )
+        .unwrap()
+        .to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_commit_ranges() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = testing::init(dir.path());
+
+        let first = testing::commit(&repo, &[("a.txt", Some("a"))], "Add a");
+        let second = testing::commit(&repo, &[("b.txt", Some("b"))], "Add b\n\nWith a body");
+        let third = testing::commit(&repo, &[("c.txt", Some("c"))], "Add c");
+
+        let commits = commit_range(&repo, &first, &third).unwrap();
+        assert_eq!(
+            commits
+                .iter()
+                .map(|c| (c.sha.as_str(), c.subject.as_str(), c.author.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                (third.as_str(), "Add c", "Tester"),
+                (second.as_str(), "Add b", "Tester"),
+            ]
+        );
+        assert!(commit_range(&repo, &third, &third).unwrap().is_empty());
+
+        let missing = "0123456789abcdef0123456789abcdef01234567";
+        assert_eq!(
+            commit_range(&repo, missing, &third).unwrap_err().code(),
+            git2::ErrorCode::NotFound
+        );
+    }
+
+    #[test]
+    fn lists_remote_branches() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = testing::init(dir.path());
+        let first = testing::commit(&repo, &[("a.txt", Some("a"))], "Add a");
+        repo.branch(
+            "release/2.4",
+            &repo.find_commit(first.parse().unwrap()).unwrap(),
+            false,
+        )
+        .unwrap();
+        let second = testing::commit(&repo, &[("b.txt", Some("b"))], "Add b");
+        repo.tag_lightweight("v2.4.0", &repo.revparse_single(&first).unwrap(), false)
+            .unwrap();
+
+        let mut branches = ls_remote(&format!("file://{}", dir.path().display())).unwrap();
+        branches.sort();
+
+        assert_eq!(
+            branches,
+            vec![
+                ("master".to_string(), second),
+                ("release/2.4".to_string(), first),
+            ]
+        );
+    }
+
+    #[test]
+    fn lists_changed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = testing::init(dir.path());
+
+        let first = testing::commit(
+            &repo,
+            &[("README.md", Some("a")), ("src/main.cpp", Some("a"))],
+            "Initial commit",
+        );
+        testing::commit(
+            &repo,
+            &[("src/main.cpp", Some("b")), ("src/app/app.cpp", Some("b"))],
+            "Add the app",
+        );
+        let third = testing::commit(&repo, &[("README.md", None)], "Remove the readme");
+
+        assert_eq!(
+            changed_files(&repo, &first, &third).unwrap(),
+            vec!["README.md", "src/app/app.cpp", "src/main.cpp"]
+        );
+        assert!(changed_files(&repo, &third, &third).unwrap().is_empty());
+
+        let missing = "0123456789abcdef0123456789abcdef01234567";
+        assert_eq!(
+            changed_files(&repo, missing, &third).unwrap_err().code(),
+            git2::ErrorCode::NotFound
+        );
     }
-    Ok(())
 }
@@ -0,0 +1,185 @@
+use std::{sync::Arc, time::Duration};
+
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::{Deserialize, Serialize};
+
+#[allow(unused)]
+use tracing::log::*;
+
+use super::Error;
+use crate::build::now;
+
+// Installation access tokens are refreshed this long before they expire,
+// so a token never expires in the middle of a long upload
+const INSTALLATION_TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(15 * 60);
+
+/// How requests to the GitHub API are authenticated
+#[derive(Clone)]
+pub enum Auth {
+    // A personal access token
+    Token(String),
+
+    // A GitHub App installation
+    App(Arc<AppAuth>),
+}
+
+impl Auth {
+    /// Returns the value of the Authorization header to send
+    pub async fn authorization(&self) -> Result<String, Error> {
+        match self {
+            Auth::Token(token) => Ok(format!("Bearer {token}")),
+            Auth::App(app) => Ok(format!("Bearer {}", app.installation_token().await?)),
+        }
+    }
+
+    /// Forgets the token sent with the given Authorization header after the API rejected it,
+    /// e.g. because it was revoked, so the next request gets a new one
+    pub async fn invalidate(&self, authorization: &str) {
+        if let Auth::App(app) = self {
+            let mut token = app.token.lock().await;
+            if token
+                .as_ref()
+                .is_some_and(|t| authorization == format!("Bearer {}", t.token))
+            {
+                warn!(
+                    "The access token of installation {} was rejected",
+                    app.installation_id
+                );
+                *token = None;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Claims {
+    // Issued at, backdated to allow for clock drift
+    iat: u64,
+    // Expires at, at most 10 minutes in the future
+    exp: u64,
+    // The app ID
+    iss: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AccessTokenResponse {
+    token: String,
+
+    // e.g. 2016-07-11T22:14:10Z
+    expires_at: String,
+}
+
+#[derive(Debug, Clone)]
+struct InstallationToken {
+    token: String,
+
+    // Unix timestamp (in seconds) after which the token is replaced with a new one
+    refresh_at: u64,
+}
+
+/// Authenticates as an installation of a GitHub App.
+/// A JWT signed with the app's private key is exchanged for an installation access token,
+/// which is cached until shortly before it expires.
+pub struct AppAuth {
+    http: reqwest::Client,
+
+    api_base_url: String,
+
+    app_id: u64,
+    installation_id: u64,
+    key: EncodingKey,
+
+    token: tokio::sync::Mutex<Option<InstallationToken>>,
+}
+
+impl AppAuth {
+    pub fn new(
+        http: reqwest::Client,
+        api_base_url: &str,
+        app_id: u64,
+        installation_id: u64,
+        private_key_pem: &[u8],
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            http,
+            api_base_url: api_base_url.trim_end_matches('/').to_string(),
+            app_id,
+            installation_id,
+            key: EncodingKey::from_rsa_pem(private_key_pem)?,
+            token: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    /// Returns the same app's authentication for another installation, e.g. on another account
+    pub fn for_installation(&self, installation_id: u64) -> Self {
+        Self {
+            http: self.http.clone(),
+            api_base_url: self.api_base_url.clone(),
+            app_id: self.app_id,
+            installation_id,
+            key: self.key.clone(),
+            token: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    pub fn app_id(&self) -> u64 {
+        self.app_id
+    }
+
+    fn jwt(&self) -> Result<String, Error> {
+        let now = now();
+        let claims = Claims {
+            iat: now - 60,
+            exp: now + 9 * 60,
+            iss: self.app_id.to_string(),
+        };
+
+        jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &self.key)
+            .map_err(|e| Error::Auth(e.to_string()))
+    }
+
+    async fn installation_token(&self) -> Result<String, Error> {
+        // Holding the lock while refreshing makes concurrent requests wait for a single refresh
+        let mut token = self.token.lock().await;
+
+        if let Some(token) = token.as_ref().filter(|t| t.refresh_at > now()) {
+            return Ok(token.token.clone());
+        }
+
+        info!(
+            "Requesting a new access token for installation {}",
+            self.installation_id
+        );
+
+        let res = self
+            .http
+            .post(format!(
+                "{}/app/installations/{}/access_tokens",
+                self.api_base_url, self.installation_id
+            ))
+            .bearer_auth(self.jwt()?)
+            .send()
+            .await?;
+
+        let status = res.status();
+        let headers = res.headers().clone();
+        let body = res.bytes().await?;
+        if !status.is_success() {
+            return Err(Error::from_response(status, &headers, &body));
+        }
+
+        let response: AccessTokenResponse = serde_json::from_slice(&body)?;
+        let expires_at = chrono::DateTime::parse_from_rfc3339(&response.expires_at)
+            .map_err(|e| Error::Auth(format!("Invalid expires_at {}: {e}", response.expires_at)))?
+            .timestamp();
+
+        let new_token = InstallationToken {
+            token: response.token,
+            refresh_at: u64::try_from(expires_at)
+                .unwrap_or_default()
+                .saturating_sub(INSTALLATION_TOKEN_REFRESH_MARGIN.as_secs()),
+        };
+
+        Ok(token.insert(new_token).token.clone())
+    }
+}
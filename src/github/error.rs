@@ -0,0 +1,159 @@
+use std::{fmt, time::Duration};
+
+use reqwest::{header::HeaderMap, StatusCode};
+use serde::Deserialize;
+
+/// An error returned by the GitHub API
+#[derive(Debug)]
+pub enum Error {
+    // 404: The resource doesn't exist, or the token isn't allowed to see it
+    NotFound(String),
+
+    // 401: The token is missing, invalid or expired
+    Unauthorized(String),
+
+    // 403: The token isn't allowed to do this
+    Forbidden(String),
+
+    // 422: The request was invalid, e.g. an asset with the same name already exists
+    Unprocessable(String),
+
+    // 403/429: The primary or secondary rate limit was hit
+    RateLimited {
+        message: String,
+
+        // How long GitHub asked us to wait before retrying, from the Retry-After header
+        retry_after: Option<Duration>,
+
+        // Unix timestamp (in seconds) when the rate limit resets, from the X-RateLimit-Reset header
+        reset: Option<u64>,
+    },
+
+    // Any other non-successful status code
+    Status {
+        status: StatusCode,
+        message: String,
+    },
+
+    // The request couldn't be sent, or the response couldn't be read
+    Request(reqwest::Error),
+
+    // The response body wasn't what we expected
+    Decode(serde_json::Error),
+
+    // Reading the file to upload failed
+    Io(std::io::Error),
+
+    // A configured base URL isn't a valid URL
+    InvalidUrl(String),
+
+    // Creating the credentials for a request failed
+    Auth(String),
+
+    // A request with a streaming body can't be copied to retry it
+    StreamingBody,
+}
+
+// https://docs.github.com/en/rest/overview/resources-in-the-rest-api#client-errors
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    message: String,
+}
+
+fn header<T: std::str::FromStr>(headers: &HeaderMap, name: &str) -> Option<T> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+impl Error {
+    /// Classifies a non-successful response from the GitHub API
+    pub fn from_response(status: StatusCode, headers: &HeaderMap, body: &[u8]) -> Self {
+        let message = serde_json::from_slice::<ErrorResponse>(body)
+            .map(|r| r.message)
+            .unwrap_or_else(|_| String::from_utf8_lossy(body).into_owned());
+
+        let retry_after = header::<u64>(headers, "retry-after").map(Duration::from_secs);
+        let rate_limit_exhausted = header::<u64>(headers, "x-ratelimit-remaining") == Some(0);
+
+        match status {
+            StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS
+                if retry_after.is_some() || rate_limit_exhausted =>
+            {
+                Error::RateLimited {
+                    message,
+                    retry_after,
+                    reset: header(headers, "x-ratelimit-reset"),
+                }
+            }
+            StatusCode::TOO_MANY_REQUESTS => Error::RateLimited {
+                message,
+                retry_after,
+                reset: None,
+            },
+            StatusCode::NOT_FOUND => Error::NotFound(message),
+            StatusCode::UNAUTHORIZED => Error::Unauthorized(message),
+            StatusCode::FORBIDDEN => Error::Forbidden(message),
+            StatusCode::UNPROCESSABLE_ENTITY => Error::Unprocessable(message),
+            status => Error::Status { status, message },
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound(message) => write!(f, "not found: {message}"),
+            Error::Unauthorized(message) => write!(f, "unauthorized: {message}"),
+            Error::Forbidden(message) => write!(f, "forbidden: {message}"),
+            Error::Unprocessable(message) => write!(f, "unprocessable: {message}"),
+            Error::RateLimited {
+                message,
+                retry_after,
+                reset,
+            } => {
+                write!(f, "rate limited: {message}")?;
+                if let Some(retry_after) = retry_after {
+                    write!(f, " (retry after {}s)", retry_after.as_secs())?;
+                } else if let Some(reset) = reset {
+                    write!(f, " (resets at {reset})")?;
+                }
+                Ok(())
+            }
+            Error::Status { status, message } => write!(f, "{status}: {message}"),
+            Error::Request(e) => write!(f, "request failed: {e}"),
+            Error::Decode(e) => write!(f, "unexpected response: {e}"),
+            Error::Io(e) => write!(f, "io error: {e}"),
+            Error::InvalidUrl(e) => write!(f, "invalid url: {e}"),
+            Error::Auth(e) => write!(f, "authentication failed: {e}"),
+            Error::StreamingBody => write!(f, "a request with a streaming body can't be retried"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Request(e) => Some(e),
+            Error::Decode(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Request(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Decode(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -11,11 +11,33 @@ pub struct Root {
     // The commit hash after this push
     pub after: String,
 
+    // Whether the push overwrote the branch's history
+    #[serde(default)]
+    pub forced: bool,
+
+    // The pushed commits, oldest first. GitHub lists at most 20
+    #[serde(default)]
+    pub commits: Vec<Commit>,
+
+    // The commit the branch points to after the push, None if the branch was deleted
+    pub head_commit: Option<Commit>,
+
     pub repository: Repository,
 
     pub sender: Sender,
 }
 
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Commit {
+    pub id: String,
+    pub message: String,
+
+    // Paths of the files the commit changed, None if they weren't listed
+    pub added: Option<Vec<String>>,
+    pub removed: Option<Vec<String>>,
+    pub modified: Option<Vec<String>>,
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Repository {
     pub id: i64,
@@ -49,13 +71,93 @@ pub struct Sender {
     pub url: String,
 }
 
+// https://docs.github.com/en/webhooks/webhook-events-and-payloads#pull_request
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PullRequestEvent {
+    // opened, synchronize, labeled, closed, ...
+    pub action: String,
+
+    pub number: u64,
+
+    pub pull_request: PullRequest,
+
+    // The label that was added, for labeled events
+    pub label: Option<Label>,
+
+    pub repository: RepositoryName,
+
+    pub sender: Sender,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PullRequest {
+    pub number: u64,
+    pub state: String,
+    pub user: User,
+    pub labels: Vec<Label>,
+
+    // The branch that is merged
+    pub head: PullRequestRef,
+
+    // The branch that is merged into
+    pub base: PullRequestRef,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PullRequestRef {
+    #[serde(rename = "ref")]
+    pub branch: String,
+    pub sha: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Label {
+    pub name: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct User {
+    pub login: String,
+}
+
+// The repository of events whose repository fields differ from the ones of push events
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RepositoryName {
+    pub full_name: String,
+}
+
+// https://docs.github.com/en/rest/issues/comments
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IssueComment {
+    pub id: i64,
+    pub body: Option<String>,
+
+    // The author, for comments of GitHub Apps its bot user
+    pub user: Option<User>,
+
+    // The app the comment was written by, if it was written by a GitHub App
+    pub performed_via_github_app: Option<GithubApp>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GithubApp {
+    pub id: u64,
+}
+
+// https://docs.github.com/en/rest/repos/repos#get-a-repository
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GetRepositoryRoot {
+    pub id: i64,
+    pub full_name: String,
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct GetReleaseRoot {
     pub id: i64,
     pub url: String,
     pub tag_name: String,
-    pub name: String,
-    pub body: String,
+    pub name: Option<String>,
+    pub body: Option<String>,
     pub draft: bool,
     pub prerelease: bool,
     pub assets: Vec<ReleaseAsset>,
@@ -64,6 +166,7 @@ pub struct GetReleaseRoot {
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ReleaseAsset {
     pub url: String,
+    pub browser_download_url: String,
     pub id: i64,
     pub node_id: String,
     pub name: String,
@@ -91,3 +194,83 @@ pub struct UploadReleaseAssetRoot {
     pub created_at: String,
     pub updated_at: String,
 }
+
+impl From<ReleaseAsset> for UploadReleaseAssetRoot {
+    fn from(asset: ReleaseAsset) -> Self {
+        Self {
+            url: asset.url,
+            browser_download_url: asset.browser_download_url,
+            id: asset.id,
+            node_id: asset.node_id,
+            name: asset.name,
+            label: asset.label.unwrap_or_default(),
+            state: asset.state,
+            content_type: asset.content_type,
+            size: asset.size,
+            download_count: asset.download_count,
+            created_at: asset.created_at,
+            updated_at: asset.updated_at,
+        }
+    }
+}
+
+// https://docs.github.com/en/rest/checks/runs
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CheckRun {
+    pub id: i64,
+    pub name: String,
+    pub head_sha: String,
+    pub status: String,
+    pub conclusion: Option<String>,
+}
+
+/// Fields of a check run to change, fields that are None are left as they are
+#[derive(Default, Debug, Clone, PartialEq, Serialize)]
+pub struct CheckRunUpdate {
+    // queued, in_progress or completed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+
+    // success, failure, cancelled, ... Setting this completes the check run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conclusion: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<CheckRunOutput>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize)]
+pub struct CheckRunOutput {
+    pub title: String,
+
+    // Markdown
+    pub summary: String,
+
+    // Markdown, shown below the summary
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+
+    // At most 50 per request
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub annotations: Vec<CheckRunAnnotation>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize)]
+pub struct CheckRunAnnotation {
+    // Relative to the root of the repo
+    pub path: String,
+
+    pub start_line: u32,
+    pub end_line: u32,
+
+    // Columns may only be set if the annotation spans a single line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_column: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_column: Option<u32>,
+
+    // notice, warning or failure
+    pub annotation_level: String,
+
+    pub message: String,
+}
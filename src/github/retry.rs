@@ -0,0 +1,67 @@
+use std::time::Duration;
+
+use super::Error;
+
+/// Decides whether & when a failed GitHub request is retried
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // How often a request is retried before giving up
+    pub max_retries: u32,
+
+    // Delay before the first retry, doubled for every following retry
+    pub initial_delay: Duration,
+
+    // Upper bound for the exponential backoff
+    pub max_delay: Duration,
+
+    // Give up instead of waiting if GitHub asks us to wait longer than this for a rate limit
+    pub max_rate_limit_wait: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(2 * 60),
+            max_rate_limit_wait: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns how long to wait before retrying a request that failed with the given error,
+    /// or None if it shouldn't be retried.
+    /// `retry` is the number of the upcoming retry, starting at 1. Requests that aren't idempotent
+    /// are only retried if they certainly weren't processed, so they can't be applied twice
+    pub fn delay(&self, e: &Error, retry: u32, idempotent: bool) -> Option<Duration> {
+        if retry > self.max_retries {
+            return None;
+        }
+
+        let backoff = self
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(retry - 1))
+            .min(self.max_delay);
+
+        match e {
+            Error::RateLimited {
+                retry_after, reset, ..
+            } => {
+                let wait = match (retry_after, reset) {
+                    (Some(retry_after), _) => *retry_after,
+                    // Add a second of leeway, the reset timestamp has a resolution of a second
+                    (None, Some(reset)) => {
+                        Duration::from_secs(reset.saturating_sub(crate::build::now()) + 1)
+                    }
+                    (None, None) => backoff,
+                };
+
+                (wait <= self.max_rate_limit_wait).then_some(wait)
+            }
+            Error::Status { status, .. } if idempotent && status.is_server_error() => Some(backoff),
+            Error::Request(e) if !e.is_builder() && (idempotent || e.is_connect()) => Some(backoff),
+            _ => None,
+        }
+    }
+}
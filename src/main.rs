@@ -3,11 +3,17 @@ mod build;
 mod config;
 mod git;
 mod github;
+mod poll;
+mod preflight;
+mod schedule;
+mod state;
+mod template;
 mod web;
 
 use std::sync::Arc;
 
-use build::pipeline::Pipeline;
+#[allow(unused)]
+use tracing::log::*;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -16,39 +22,49 @@ async fn main() -> anyhow::Result<()> {
     // TODO: Add the ability to specify a custom config path
     let cfg = config::read("config.toml")?;
 
-    let github_client = github::client::build(&cfg.github)?;
-
-    let default_build_config = cfg.build.default_config.clone();
-    let pipelines: build::Pipelines = cfg
-        .github
-        .branches
-        .iter()
-        .map(|branch| {
-            let repo_owner = cfg.github.repo_owner.clone();
-            let repo_name = cfg.github.repo_name.clone();
-            (
-                branch.name.clone(),
-                cfg.build
-                    .configs
-                    .iter()
-                    .map(|c| {
-                        Arc::new(Pipeline::new(
-                            github_client.clone(),
-                            &cfg.build.repo_dir,
-                            &cfg.build.dmg_output_path,
-                            repo_owner.clone(),
-                            repo_name.clone(),
-                            branch,
-                            &default_build_config,
-                            c.clone(),
-                        ))
-                    })
-                    .collect(),
-            )
-        })
-        .collect();
-
-    web::start_server(cfg, pipelines, github_client).await?;
+    let state = Arc::new(build::State::open(std::path::Path::new(
+        &cfg.build.state_dir,
+    ))?);
+
+    let mut repositories = vec![];
+    let mut github_client: Option<github::GithubClient> = None;
+
+    for repository in &cfg.repositories {
+        // All repositories share the same connection pool & credentials
+        let mut client = match &github_client {
+            Some(client) => client.for_repository(&repository.owner, &repository.name),
+            None => github::GithubClient::new(&cfg.github, &repository.owner, &repository.name)?,
+        };
+        github_client.get_or_insert_with(|| client.clone());
+
+        // e.g. a fork owned by another account, which has its own installation of the app
+        if let Some(installation_id) = repository.installation_id {
+            client = client.for_installation(installation_id);
+        }
+
+        repositories.push(build::Repository::new(
+            &cfg,
+            repository,
+            client,
+            state.clone(),
+        )?);
+    }
+
+    let repositories = Arc::new(build::Repositories::new(repositories));
+
+    if let Some(full_name) = cfg.github.legacy_full_name() {
+        // The state of a repository configured in the github section was kept without its name
+        state.prefix_legacy_keys(&full_name)?;
+    }
+
+    preflight::run(&repositories).await?;
+
+    let jobs = Arc::new(build::Jobs::default());
+
+    poll::start(repositories.clone(), jobs.clone(), state.clone());
+    schedule::start(repositories.clone(), jobs.clone());
+
+    web::start_server(cfg, repositories, jobs, state).await?;
 
     Ok(())
 }
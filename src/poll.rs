@@ -0,0 +1,187 @@
+use std::{collections::HashMap, sync::Arc};
+
+use tokio::time::MissedTickBehavior;
+
+#[allow(unused)]
+use tracing::log::*;
+
+use crate::build::{BuildRequest, Jobs, Repositories, Repository, State};
+
+/// Starts polling the branches of every repository with a poll interval, for builders that
+/// can't receive webhooks. A build is enqueued whenever the head of a built branch changes
+pub fn start(repositories: Arc<Repositories>, jobs: Arc<Jobs>, state: Arc<State>) {
+    for repository in repositories.iter() {
+        let Some(poll_interval) = repository.poll_interval else {
+            continue;
+        };
+
+        info!(
+            "Polling {} every {}s",
+            repository.full_name,
+            poll_interval.as_secs()
+        );
+
+        let full_name = repository.full_name.clone();
+        let (repositories, jobs, state) = (repositories.clone(), jobs.clone(), state.clone());
+
+        tokio::spawn(async move {
+            let repository = repositories
+                .get(&full_name)
+                .expect("repositories don't change while running");
+
+            let mut interval = tokio::time::interval(poll_interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            loop {
+                interval.tick().await;
+                poll(repository, &jobs, &state).await;
+            }
+        });
+    }
+}
+
+/// A branch whose head changed since it was last seen
+#[derive(Debug, PartialEq)]
+struct MovedHead {
+    branch: String,
+    commit: String,
+
+    // None if the branch wasn't seen before
+    last_seen: Option<String>,
+}
+
+/// Returns the branches of the repository whose head differs from the saved one
+fn moved_heads(
+    full_name: &str,
+    heads: Vec<(String, String)>,
+    seen: &HashMap<String, String>,
+) -> Vec<MovedHead> {
+    heads
+        .into_iter()
+        .filter_map(|(branch, commit)| {
+            let last_seen = seen
+                .get(&State::branch_head_key(full_name, &branch))
+                .cloned();
+            (last_seen.as_deref() != Some(commit.as_str())).then_some(MovedHead {
+                branch,
+                commit,
+                last_seen,
+            })
+        })
+        .collect()
+}
+
+/// Enqueues a build of every branch whose head changed since it was last built.
+/// Branches that weren't seen before are built as well
+async fn poll(repository: &Repository, jobs: &Jobs, state: &Arc<State>) {
+    // A new job would abort the builds that were enqueued by the last poll
+    if jobs.is_running(repository.repo_dir()) {
+        debug!(
+            "Not polling {} while it's being built",
+            repository.full_name
+        );
+        return;
+    }
+
+    let url = repository.clone_url();
+    let heads = tokio::task::spawn_blocking(move || crate::git::ls_remote(&url))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|heads| Ok(heads?));
+
+    let heads = match heads {
+        Ok(heads) => heads,
+        Err(e) => {
+            warn!("Failed polling {}: {e}", repository.full_name);
+            return;
+        }
+    };
+
+    repository.forget_deleted_branches(|branch| heads.iter().any(|(head, _)| head == branch));
+
+    let seen = state.branch_heads.read(|heads| heads.clone());
+    let mut builds = vec![];
+    let mut moved = vec![];
+
+    for head in moved_heads(&repository.full_name, heads, &seen) {
+        let Some(pipelines) = repository.pipelines(&head.branch) else {
+            continue;
+        };
+
+        info!(
+            "{} of {} moved from {} to {}",
+            head.branch,
+            repository.full_name,
+            head.last_seen.as_deref().unwrap_or("nothing"),
+            head.commit
+        );
+
+        builds.push((
+            pipelines,
+            BuildRequest {
+                commit: Some(head.commit.clone()),
+                before: head.last_seen.clone(),
+                ..Default::default()
+            },
+        ));
+        moved.push(head);
+    }
+
+    // A head is only saved once it's built, so the next poll enqueues the branches again if the
+    // job is aborted, e.g. by a webhook or a restart
+    let (full_name, state) = (repository.full_name.clone(), state.clone());
+    jobs.spawn_all_then(builds, move |i| {
+        state.set_branch_head(&full_name, &moved[i].branch, &moved[i].commit);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_moved_heads() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = crate::git::testing::init(dir.path());
+        let first = crate::git::testing::commit(&repo, &[("a.txt", Some("a"))], "Add a");
+        repo.branch(
+            "stable",
+            &repo.find_commit(first.parse().unwrap()).unwrap(),
+            false,
+        )
+        .unwrap();
+        let second = crate::git::testing::commit(&repo, &[("b.txt", Some("b"))], "Add b");
+        repo.branch(
+            "new",
+            &repo.find_commit(second.parse().unwrap()).unwrap(),
+            false,
+        )
+        .unwrap();
+
+        let url = format!("file://{}", dir.path().display());
+        let seen = HashMap::from([
+            ("owner/repo/master".to_string(), first.clone()),
+            ("owner/repo/stable".to_string(), first.clone()),
+            ("owner/other/new".to_string(), first.clone()),
+        ]);
+
+        let mut moved = moved_heads("owner/repo", crate::git::ls_remote(&url).unwrap(), &seen);
+        moved.sort_by(|a, b| a.branch.cmp(&b.branch));
+
+        assert_eq!(
+            moved,
+            vec![
+                MovedHead {
+                    branch: "master".to_string(),
+                    commit: second.clone(),
+                    last_seen: Some(first),
+                },
+                MovedHead {
+                    branch: "new".to_string(),
+                    commit: second,
+                    last_seen: None,
+                },
+            ]
+        );
+    }
+}
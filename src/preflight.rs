@@ -0,0 +1,309 @@
+use std::collections::BTreeMap;
+
+#[allow(unused)]
+use tracing::log::*;
+
+use crate::build::{Repositories, Repository};
+
+/// Checks that the configured repos & releases can be accessed before the server starts,
+/// so a bad token or release doesn't only show up once the first build tries to upload.
+/// Returns an error listing every problem that was found
+pub async fn run(repositories: &Repositories) -> anyhow::Result<()> {
+    let mut problems: Vec<String> = vec![];
+
+    for repository in repositories.iter() {
+        check_repository(repository, &mut problems).await;
+    }
+
+    if !problems.is_empty() {
+        anyhow::bail!(
+            "Preflight checks failed:\n{}",
+            problems
+                .iter()
+                .map(|problem| format!("  - {problem}"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+    }
+
+    info!("Preflight checks passed");
+
+    Ok(())
+}
+
+async fn check_repository(repository: &Repository, problems: &mut Vec<String>) {
+    let github_client = &repository.github_client;
+
+    let repo_name = github_client.repo_full_name();
+    if let Err(e) = github_client.get_repository().await {
+        problems.push(format!("Repository {repo_name}: fetching it failed: {e}"));
+    }
+
+    // Release ID -> (tag, asset name -> pipelines uploading it)
+    let mut releases: BTreeMap<i64, (String, BTreeMap<&str, Vec<String>>)> = BTreeMap::new();
+
+    for pipeline in repository.configured_pipelines() {
+        // Missing releases are only created by builds, not by checking the config
+        match pipeline.find_release().await {
+            Ok(Some(release)) => {
+                info!(
+                    "{}: using release {} ({})",
+                    pipeline.display_name(),
+                    release.tag_name,
+                    release.id
+                );
+
+                releases
+                    .entry(release.id)
+                    .or_insert_with(|| (release.tag_name, BTreeMap::new()))
+                    .1
+                    .entry(pipeline.asset_name())
+                    .or_default()
+                    .push(pipeline.display_name());
+            }
+            Ok(None) => info!(
+                "{}: the release doesn't exist yet, the first build creates it",
+                pipeline.display_name()
+            ),
+            Err(e) => problems.push(format!("{}: {e:#}", pipeline.display_name())),
+        }
+    }
+
+    // The assets of pull requests are named after them, so they can't clash with other assets
+    let pull_request_tag = repository
+        .pull_requests
+        .as_ref()
+        .and_then(|pull_requests| pull_requests.release_tag());
+    if let Some(tag) = pull_request_tag {
+        match github_client.get_release_by_tag(tag).await {
+            Ok(release) => {
+                info!(
+                    "Pull requests of {repo_name}: using release {} ({})",
+                    release.tag_name, release.id
+                );
+                releases
+                    .entry(release.id)
+                    .or_insert_with(|| (release.tag_name, BTreeMap::new()));
+            }
+            Err(crate::github::Error::NotFound(_)) => info!(
+                "Pull requests of {repo_name}: the release {tag} doesn't exist yet, the first build creates it"
+            ),
+            Err(e) => problems.push(format!(
+                "Pull requests of {repo_name}: getting release {tag}: {e}"
+            )),
+        }
+    }
+
+    for (release_id, (tag, assets)) in releases {
+        // The role of a token's user doesn't tell what the token may do, e.g. for fine-grained
+        // tokens, so writing is tried instead
+        if let Err(e) = github_client.check_release_write_access(release_id).await {
+            problems.push(format!(
+                "Release {tag} ({release_id}) of {repo_name}: the credentials can't edit it, which is needed to upload release assets: {e}"
+            ));
+        }
+
+        for (asset_name, pipelines) in assets {
+            if pipelines.len() > 1 {
+                problems.push(format!(
+                    "Release {tag} ({release_id}) of {repo_name}: asset {asset_name} is uploaded by more than one pipeline: {}",
+                    pipelines.join(", ")
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+    use wiremock::{
+        matchers::{body_json, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+    use crate::build::State;
+    use crate::github::{client::testing::release, GithubClient};
+
+    /// Reads a config building master & stable of owner/repo with the GitHub API on the server
+    async fn repositories(server: &MockServer, dir: &std::path::Path) -> Repositories {
+        repositories_with(server, dir, "").await
+    }
+
+    /// Same as `repositories`, with the given lines added to the repository's config
+    async fn repositories_with(
+        server: &MockServer,
+        dir: &std::path::Path,
+        repository: &str,
+    ) -> Repositories {
+        let path = dir.join("config.toml");
+        std::fs::write(
+            &path,
+            format!(
+                r#"
+[web]
+bind = ["127.0.0.1:0"]
+
+[github]
+token = "test-token"
+api_base_url = "{uri}"
+upload_base_url = "{uri}"
+verify_signature = false
+
+[build]
+dmg_output_path = "chatterino.dmg"
+state_dir = "{dir}/state"
+
+[[repositories]]
+owner = "owner"
+name = "repo"
+repo_dir = "{dir}/clone"
+branches = [
+    {{ name = "master", release_tag = "nightly-build" }},
+    {{ name = "stable", release_id = 2 }},
+]
+{repository}
+
+[[repositories.configs]]
+cmake_args = []
+package_envs = []
+build_dir = "build"
+asset_name = "Chatterino.dmg"
+"#,
+                uri = server.uri(),
+                dir = dir.display(),
+            ),
+        )
+        .unwrap();
+
+        let cfg = crate::config::read(path.to_str().unwrap()).unwrap();
+        let state = Arc::new(State::open(std::path::Path::new(&cfg.build.state_dir)).unwrap());
+        let repository = &cfg.repositories[0];
+        let client = GithubClient::new(&cfg.github, &repository.owner, &repository.name).unwrap();
+
+        Repositories::new(vec![
+            Repository::new(&cfg, repository, client, state).unwrap()
+        ])
+    }
+
+    async fn mount_releases(server: &MockServer, nightly_build: ResponseTemplate) {
+        let mut stable = release(vec![]);
+        stable["id"] = json!(2);
+        stable["tag_name"] = json!("stable");
+
+        Mock::given(method("GET"))
+            .and(path("/repos/owner/repo"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": 1,
+                "full_name": "owner/repo",
+            })))
+            .mount(server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/repos/owner/repo/releases/tags/nightly-build"))
+            .respond_with(nightly_build)
+            .mount(server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/repos/owner/repo/releases/2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(stable))
+            .mount(server)
+            .await;
+        // Releases are only looked up
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(201))
+            .expect(0)
+            .mount(server)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn checks_write_access_to_releases() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = MockServer::start().await;
+        mount_releases(
+            &server,
+            ResponseTemplate::new(200).set_body_json(release(vec![])),
+        )
+        .await;
+        for id in [1, 2] {
+            Mock::given(method("PATCH"))
+                .and(path(format!("/repos/owner/repo/releases/{id}")))
+                .and(body_json(json!({})))
+                .respond_with(ResponseTemplate::new(200).set_body_json(release(vec![])))
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+
+        run(&repositories(&server, dir.path()).await).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_missing_write_access() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = MockServer::start().await;
+        // The missing release isn't a problem, the first build creates it
+        mount_releases(&server, ResponseTemplate::new(404)).await;
+        Mock::given(method("PATCH"))
+            .and(path("/repos/owner/repo/releases/2"))
+            .respond_with(ResponseTemplate::new(403).set_body_json(json!({
+                "message": "Resource not accessible by personal access token",
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let e = run(&repositories(&server, dir.path()).await)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("Release stable (2) of owner/repo: the credentials can't edit it"));
+        assert!(!e.contains("nightly-build"));
+    }
+
+    #[tokio::test]
+    async fn checks_the_release_of_pull_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = MockServer::start().await;
+        mount_releases(&server, ResponseTemplate::new(404)).await;
+        let mut pr_artifacts = release(vec![]);
+        pr_artifacts["id"] = json!(3);
+        pr_artifacts["tag_name"] = json!("pr-artifacts");
+        Mock::given(method("GET"))
+            .and(path("/repos/owner/repo/releases/tags/pr-artifacts"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(pr_artifacts))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("PATCH"))
+            .and(path("/repos/owner/repo/releases/2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(release(vec![])))
+            .mount(&server)
+            .await;
+        Mock::given(method("PATCH"))
+            .and(path("/repos/owner/repo/releases/3"))
+            .respond_with(ResponseTemplate::new(403).set_body_json(json!({
+                "message": "Resource not accessible by integration",
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let repositories = repositories_with(
+            &server,
+            dir.path(),
+            &format!(
+                "[repositories.pull_requests]\nwork_dir = \"{}/prs\"\nrelease_tag = \"pr-artifacts\"",
+                dir.path().display()
+            ),
+        )
+        .await;
+
+        let e = run(&repositories).await.unwrap_err().to_string();
+        assert!(e.contains("Release pr-artifacts (3) of owner/repo: the credentials can't edit it"));
+    }
+}
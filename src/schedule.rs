@@ -0,0 +1,174 @@
+use std::{str::FromStr, sync::Arc};
+
+use anyhow::Context;
+use chrono::Local;
+use cron::Schedule;
+
+#[allow(unused)]
+use tracing::log::*;
+
+use crate::build::{BuildRequest, Jobs, Repositories};
+
+/// Parses a cron expression. Crontab's 5 fields (minute, hour, day of month, month & day of week)
+/// are accepted as well as the 6-7 fields of the cron crate, which start with the seconds.
+/// Crontab numbers the days of the week from 0 (or 7) for Sunday to 6 for Saturday, the cron crate
+/// from 1 for Sunday to 7 for Saturday
+pub fn parse(expression: &str) -> anyhow::Result<Schedule> {
+    let fields: Vec<&str> = expression.split_whitespace().collect();
+    let expression = match fields.as_slice() {
+        [minute, hour, day_of_month, month, day_of_week] => format!(
+            "0 {minute} {hour} {day_of_month} {month} {}",
+            crontab_day_of_week(day_of_week)
+                .context(format!("Invalid cron expression {expression}"))?
+        ),
+        _ => expression.trim().to_string(),
+    };
+
+    Schedule::from_str(&expression).context(format!("Invalid cron expression {expression}"))
+}
+
+/// Converts the numbers of a crontab day of week field to the ones of the cron crate, e.g. "1-5"
+/// (Monday to Friday) becomes "2-6". Names are kept as they are
+fn crontab_day_of_week(field: &str) -> anyhow::Result<String> {
+    let day = |day: &str| match day.parse::<u8>() {
+        Ok(7) => Ok("1".to_string()),
+        Ok(day @ 0..=6) => Ok((day + 1).to_string()),
+        Ok(day) => Err(anyhow::anyhow!("There's no day of week {day}")),
+        Err(_) => Ok(day.to_string()),
+    };
+
+    let items = field.split(',').map(|item| {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, Some(step)),
+            None => (item, None),
+        };
+
+        let range = match range.split_once('-') {
+            // Sunday is the first day for the cron crate, so a range up to crontab's 7 wraps
+            Some((start, "7")) if step.is_none() => format!("{}-7,1", day(start)?),
+            Some((_, "7")) => {
+                return Err(anyhow::anyhow!(
+                "Steps of days of week ending on Sunday (7) aren't supported, end the range on 6"
+            ))
+            }
+            Some((start, end)) => format!("{}-{}", day(start)?, day(end)?),
+            None => day(range)?,
+        };
+
+        Ok(match step {
+            Some(step) => format!("{range}/{step}"),
+            None => range,
+        })
+    });
+
+    Ok(items.collect::<anyhow::Result<Vec<_>>>()?.join(","))
+}
+
+/// Starts enqueueing the scheduled builds of every repository, e.g. a nightly clean build that
+/// catches toolchain changes even if nothing was pushed
+pub fn start(repositories: Arc<Repositories>, jobs: Arc<Jobs>) {
+    for repository in repositories.iter() {
+        for cfg in repository.schedules() {
+            let schedule = parse(&cfg.cron).expect("validated when reading the config");
+            let branch = cfg.branch.clone();
+            let clean = cfg.clean.unwrap_or(false);
+
+            info!(
+                "Building {branch} of {} on the schedule {}",
+                repository.full_name, cfg.cron
+            );
+
+            let full_name = repository.full_name.clone();
+            let (repositories, jobs) = (repositories.clone(), jobs.clone());
+
+            tokio::spawn(async move {
+                let repository = repositories
+                    .get(&full_name)
+                    .expect("repositories don't change while running");
+
+                let mut last = Local::now();
+                loop {
+                    let Some(next) = schedule.after(&last).next() else {
+                        info!("The schedule of {branch} of {full_name} has no upcoming builds");
+                        return;
+                    };
+
+                    tokio::time::sleep((next - Local::now()).to_std().unwrap_or_default()).await;
+                    // If the builder was suspended, the builds it missed are skipped
+                    last = next.max(Local::now());
+
+                    let pipelines = repository
+                        .pipelines(&branch)
+                        .expect("validated when creating the repository");
+
+                    info!(
+                        "Starting the scheduled {}build of {branch} of {full_name}",
+                        if clean { "clean " } else { "" }
+                    );
+                    jobs.spawn(
+                        pipelines,
+                        BuildRequest {
+                            clean,
+                            // Build even if nothing changed since the last build
+                            force: true,
+                            ..Default::default()
+                        },
+                    );
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    #[test]
+    fn parses_crontab_expressions() {
+        let after = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let next = |expression| parse(expression).unwrap().after(&after).next().unwrap();
+
+        assert_eq!(
+            next("0 3 * * *"),
+            Utc.with_ymd_and_hms(2024, 5, 2, 3, 0, 0).unwrap()
+        );
+        assert_eq!(
+            next("30 0 3 * * Sat"),
+            Utc.with_ymd_and_hms(2024, 5, 4, 3, 0, 30).unwrap()
+        );
+        assert_eq!(
+            next("@daily"),
+            Utc.with_ymd_and_hms(2024, 5, 2, 0, 0, 0).unwrap()
+        );
+        assert!(parse("0 3 * *").is_err());
+        assert!(parse("0 3 * * 8").is_err());
+    }
+
+    #[test]
+    fn converts_crontab_days_of_week() {
+        // Wednesday
+        let after = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let days = |expression| {
+            parse(expression)
+                .unwrap()
+                .after(&after)
+                .take(7)
+                .map(|date| date.format("%a").to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+
+        assert_eq!(days("0 3 * * 1-5"), "Thu Fri Mon Tue Wed Thu Fri");
+        assert_eq!(days("0 3 * * 0"), "Sun Sun Sun Sun Sun Sun Sun");
+        assert_eq!(days("0 3 * * 7"), "Sun Sun Sun Sun Sun Sun Sun");
+        assert_eq!(days("0 3 * * 5-7"), "Fri Sat Sun Fri Sat Sun Fri");
+        assert_eq!(days("0 3 * * 0,6"), "Sat Sun Sat Sun Sat Sun Sat");
+        assert_eq!(days("0 3 * * 1-5/2"), "Fri Mon Wed Fri Mon Wed Fri");
+        assert_eq!(days("0 3 * * Mon-Fri"), "Thu Fri Mon Tue Wed Thu Fri");
+        assert_eq!(days("0 3 * * */3"), "Sat Sun Wed Sat Sun Wed Sat");
+        assert!(parse("0 25 * * *").is_err());
+    }
+}
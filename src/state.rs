@@ -0,0 +1,62 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+
+#[allow(unused)]
+use tracing::log::*;
+
+/// A value that is persisted as JSON in the state directory, surviving restarts of the builder
+pub struct JsonStore<T> {
+    path: PathBuf,
+    value: Mutex<T>,
+}
+
+impl<T> JsonStore<T>
+where
+    T: Serialize + DeserializeOwned + Default,
+{
+    /// Loads the value from the given path, starting out with the default value if the file
+    /// doesn't exist yet
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let value = match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .context(format!("Deserializing state from {path:?}"))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => T::default(),
+            Err(e) => return Err(e).context(format!("Reading state from {path:?}")),
+        };
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .context(format!("Creating state directory {parent:?}"))?;
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            value: Mutex::new(value),
+        })
+    }
+
+    pub fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.value.lock().unwrap())
+    }
+
+    /// Modifies the value and writes it back to disk
+    pub fn update<R>(&self, f: impl FnOnce(&mut T) -> R) -> anyhow::Result<R> {
+        let mut value = self.value.lock().unwrap();
+
+        let res = f(&mut value);
+
+        // Write to a temporary file first so a crash can't leave a half-written state file behind
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(&*value)?)
+            .context(format!("Writing state to {tmp_path:?}"))?;
+        std::fs::rename(&tmp_path, &self.path)
+            .context(format!("Writing state to {:?}", self.path))?;
+
+        Ok(res)
+    }
+}
@@ -0,0 +1,75 @@
+use std::path::Path;
+
+use actix_web::{body::SizedStream, get, web, web::Data, HttpResponse};
+
+#[allow(unused)]
+use tracing::log::*;
+
+use crate::build;
+use crate::config;
+
+fn not_found() -> actix_web::Error {
+    actix_web::error::ErrorNotFound("Artifact not found")
+}
+
+/// Serves an artifact from the local artifact store of a repository
+#[tracing::instrument(skip(repositories))]
+#[get("/artifacts/{owner}/{repo}/{name}")]
+pub async fn artifact(
+    repositories: Data<build::Repositories>,
+    path: web::Path<(String, String, String)>,
+) -> actix_web::Result<actix_web::HttpResponse> {
+    let (owner, repo, name) = path.into_inner();
+
+    let dir = repositories
+        .get(&format!("{owner}/{repo}"))
+        .and_then(|repository| repository.pull_requests.as_ref())
+        .and_then(|pull_requests| pull_requests.artifact_dir())
+        .ok_or_else(not_found)?;
+
+    serve(dir, &name).await
+}
+
+/// Serves an artifact linked to before multiple repositories were supported,
+/// if the repository is configured in the github section
+#[tracing::instrument(skip(cfg, repositories))]
+#[get("/artifacts/{name}")]
+pub async fn legacy_artifact(
+    cfg: Data<config::Config>,
+    repositories: Data<build::Repositories>,
+    name: web::Path<String>,
+) -> actix_web::Result<actix_web::HttpResponse> {
+    let dir = cfg
+        .github
+        .legacy_full_name()
+        .and_then(|full_name| repositories.get(&full_name))
+        .and_then(|repository| repository.pull_requests.as_ref())
+        .and_then(|pull_requests| pull_requests.artifact_dir())
+        .ok_or_else(not_found)?;
+
+    serve(dir, &name).await
+}
+
+async fn serve(dir: &Path, name: &str) -> actix_web::Result<actix_web::HttpResponse> {
+    // Only plain file names are served, so nothing outside the store can be read.
+    // Names starting with a . are artifacts that are still being copied
+    if name.starts_with('.') || name.contains('/') || name.contains('\\') {
+        return Err(not_found());
+    }
+
+    let file = tokio::fs::File::open(dir.join(name))
+        .await
+        .map_err(|_| not_found())?;
+    let size = file.metadata().await?.len();
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"{name}\""),
+        ))
+        .body(SizedStream::new(
+            size,
+            tokio_util::io::ReaderStream::new(file),
+        )))
+}
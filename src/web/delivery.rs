@@ -0,0 +1,36 @@
+use actix_web::{HttpRequest, HttpResponse};
+
+#[allow(unused)]
+use tracing::log::*;
+
+use crate::build;
+
+/// Returns the ID GitHub assigned to the webhook delivery of the request
+pub fn delivery_id(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get("x-github-delivery")
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
+/// Remembers the delivery, and returns a response acknowledging it if it was received before.
+/// GitHub redelivers webhooks, e.g. when "Redeliver" is clicked, which mustn't start a second build
+pub fn acknowledge_duplicate(
+    state: &build::State,
+    delivery_id: Option<&str>,
+) -> Option<HttpResponse> {
+    let delivery_id = delivery_id?;
+
+    match state.record_delivery(delivery_id) {
+        Ok(true) => None,
+        Ok(false) => {
+            info!("Ignoring duplicate delivery {delivery_id}");
+            Some(HttpResponse::Ok().body(format!("Delivery {delivery_id} was already received")))
+        }
+        Err(e) => {
+            // Better to risk a duplicate build than to drop the delivery
+            warn!("Failed saving delivery {delivery_id}: {e:#}");
+            None
+        }
+    }
+}
@@ -1,23 +1,87 @@
 use std::{
+    collections::HashMap,
     future::{ready, Ready},
     rc::Rc,
+    sync::Arc,
 };
 
 use actix_http::h1;
 use actix_web::{
     dev::{Service, ServiceRequest, ServiceResponse, Transform},
-    web::Bytes,
+    web::{Bytes, BytesMut},
+    HttpMessage,
 };
-use futures_util::{future::LocalBoxFuture, FutureExt};
+use futures_util::{future::LocalBoxFuture, FutureExt, StreamExt};
 use hmac::{Hmac, KeyInit, Mac};
+use serde::Deserialize;
+use sha1::Sha1;
 use sha2::Sha256;
 
 use tracing::log::*;
 
+use crate::config::{Config, WebhookSecret};
+use crate::github::model::RepositoryName;
+
+/// The secrets webhook deliveries may be signed with
+#[derive(Debug, Clone, Default)]
+pub struct WebhookSecrets {
+    // Accepted for deliveries of any repository
+    shared: Vec<WebhookSecret>,
+
+    // Only accepted for deliveries of a single repository, by its lowercase full name
+    repositories: HashMap<String, Vec<WebhookSecret>>,
+}
+
+impl WebhookSecrets {
+    pub fn new(cfg: &Config) -> Self {
+        Self {
+            shared: cfg.github.webhook_secrets(),
+            repositories: cfg
+                .repositories
+                .iter()
+                .map(|r| (r.full_name().to_lowercase(), r.webhook_secrets()))
+                .collect(),
+        }
+    }
+
+    /// Returns the secrets the delivery may be signed with, depending on the repository it's about
+    fn for_delivery(&self, body: &[u8]) -> Vec<&WebhookSecret> {
+        // Only the repository is needed, the handler deserializes the whole event
+        #[derive(Deserialize)]
+        struct Delivery {
+            repository: Option<RepositoryName>,
+        }
+
+        let repository_secrets = serde_json::from_slice::<Delivery>(body)
+            .ok()
+            .and_then(|delivery| delivery.repository)
+            .and_then(|repository| self.repositories.get(&repository.full_name.to_lowercase()));
+
+        self.shared
+            .iter()
+            .chain(repository_secrets.into_iter().flatten())
+            .collect()
+    }
+}
+
+/// A signature sent by GitHub along with a webhook delivery
+enum Signature {
+    // x-hub-signature-256
+    Sha256(Vec<u8>),
+
+    // x-hub-signature, only accepted in legacy mode
+    Sha1(Vec<u8>),
+}
+
 pub struct VerifyGithubSignature<S> {
     service: Rc<S>,
     validate_secret: bool,
-    hasher: Hmac<Sha256>,
+    // The accepted secrets
+    secrets: Arc<WebhookSecrets>,
+    // Accept SHA-1 signatures if a delivery has no SHA-256 signature
+    legacy_sha1: bool,
+    // Bodies larger than this (in bytes) are rejected
+    max_body_size: usize,
 }
 
 impl<S, B> Service<ServiceRequest> for VerifyGithubSignature<S>
@@ -33,35 +97,40 @@ where
     fn call(&self, mut req: ServiceRequest) -> Self::Future {
         // Clone the Rc pointers so we can move them into the async block.
         let srv = self.service.clone();
-        let mut hasher = self.hasher.clone();
+        let secrets = self.secrets.clone();
         // let auth_data = self.auth_data.clone();
 
         let validate_secret = self.validate_secret;
+        let legacy_sha1 = self.legacy_sha1;
+        let max_body_size = self.max_body_size;
 
         async move {
             if validate_secret {
                 info!("Validating secret");
-                let signature_header = req
-                    .headers()
-                    .get("x-hub-signature-256")
-                    .ok_or_else(|| actix_web::error::ErrorBadRequest("missing signature header"))?;
+                let signature = signature(&req, legacy_sha1)?;
 
-                let signature_bytes = hex::decode(
-                    signature_header
-                        .to_str()
-                        .map_err(actix_web::error::ErrorBadRequest)?
-                        .strip_prefix("sha256=")
-                        .ok_or_else(|| actix_web::error::ErrorBadRequest("missing prefix"))?,
-                )
-                .map_err(actix_web::error::ErrorBadRequest)?;
+                let body = read_body(&mut req, max_body_size).await?;
 
-                let body = req.extract::<Bytes>().await.unwrap();
+                let secrets = secrets.for_delivery(&body);
 
-                hasher.update(&body);
+                let matching_secret = match &signature {
+                    Signature::Sha256(signature) => {
+                        matching_secret::<Hmac<Sha256>>(&secrets, &body, signature)
+                    }
+                    Signature::Sha1(signature) => {
+                        warn!("Verifying a legacy SHA-1 signature");
+                        matching_secret::<Hmac<Sha1>>(&secrets, &body, signature)
+                    }
+                };
 
-                hasher
-                    .verify_slice(&signature_bytes)
-                    .map_err(actix_web::error::ErrorUnauthorized)?;
+                match matching_secret {
+                    Some(id) => info!("Signature matches secret {id}"),
+                    None => {
+                        return Err(actix_web::error::ErrorUnauthorized(
+                            "signature doesn't match any secret",
+                        ))
+                    }
+                }
 
                 // re-insert body back into request to be used by handlers
                 req.set_payload(bytes_to_payload(body));
@@ -77,21 +146,101 @@ where
     }
 }
 
+/// Reads the signature from the request's headers
+fn signature(req: &ServiceRequest, legacy_sha1: bool) -> actix_web::Result<Signature> {
+    let (header, prefix) = match req.headers().get("x-hub-signature-256") {
+        Some(header) => (header, "sha256="),
+        None if legacy_sha1 => (
+            req.headers()
+                .get("x-hub-signature")
+                .ok_or_else(|| actix_web::error::ErrorBadRequest("missing signature header"))?,
+            "sha1=",
+        ),
+        None => {
+            return Err(actix_web::error::ErrorBadRequest(
+                "missing signature header",
+            ))
+        }
+    };
+
+    let signature_bytes = hex::decode(
+        header
+            .to_str()
+            .map_err(actix_web::error::ErrorBadRequest)?
+            .strip_prefix(prefix)
+            .ok_or_else(|| actix_web::error::ErrorBadRequest("missing prefix"))?,
+    )
+    .map_err(actix_web::error::ErrorBadRequest)?;
+
+    Ok(match prefix {
+        "sha1=" => Signature::Sha1(signature_bytes),
+        _ => Signature::Sha256(signature_bytes),
+    })
+}
+
+/// Reads the whole body, failing with 413 if it's larger than max_size bytes
+async fn read_body(req: &mut ServiceRequest, max_size: usize) -> actix_web::Result<Bytes> {
+    let too_large = || actix_web::error::ErrorPayloadTooLarge("payload too large");
+
+    let content_length = req
+        .headers()
+        .get("content-length")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if content_length.is_some_and(|length| length > max_size) {
+        return Err(too_large());
+    }
+
+    let mut payload = req.take_payload();
+    let mut body = BytesMut::new();
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(actix_web::error::ErrorBadRequest)?;
+
+        if body.len() + chunk.len() > max_size {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body.freeze())
+}
+
+/// Returns the ID of the secret the body was signed with, if any.
+/// Each signature is compared in constant time
+fn matching_secret<M: Mac + KeyInit>(
+    secrets: &[&WebhookSecret],
+    body: &[u8],
+    signature: &[u8],
+) -> Option<String> {
+    secrets.iter().find_map(|s| {
+        let mut hasher = <M as KeyInit>::new_from_slice(s.secret.as_bytes()).ok()?;
+        hasher.update(body);
+        hasher.verify_slice(signature).is_ok().then(|| s.id.clone())
+    })
+}
+
 #[derive(Clone)]
 pub struct VerifyGithubSignatureFactory {
     validate_secret: bool,
-    hasher: Hmac<Sha256>,
+    secrets: Arc<WebhookSecrets>,
+    legacy_sha1: bool,
+    max_body_size: usize,
 }
 
 impl VerifyGithubSignatureFactory {
-    pub fn new(validate_secret: bool, secret: &str) -> actix_web::Result<Self> {
-        let hasher = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
-            .map_err(actix_web::error::ErrorBadRequest)?;
-
-        Ok(Self {
+    pub fn new(
+        validate_secret: bool,
+        secrets: WebhookSecrets,
+        legacy_sha1: bool,
+        max_body_size: usize,
+    ) -> Self {
+        Self {
             validate_secret,
-            hasher,
-        })
+            secrets: Arc::new(secrets),
+            legacy_sha1,
+            max_body_size,
+        }
     }
 }
 
@@ -109,7 +258,9 @@ where
         ready(Ok(VerifyGithubSignature {
             service: Rc::new(service),
             validate_secret: self.validate_secret,
-            hasher: self.hasher.clone(),
+            secrets: self.secrets.clone(),
+            legacy_sha1: self.legacy_sha1,
+            max_body_size: self.max_body_size,
         }))
     }
 }
@@ -119,3 +270,184 @@ fn bytes_to_payload(buf: Bytes) -> actix_web::dev::Payload {
     pl.unread_data(buf);
     actix_web::dev::Payload::from(pl)
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, web, App};
+
+    use super::*;
+
+    // A ping delivery, with the signatures GitHub sent for it using the secret "webhook_secret"
+    const PING: &[u8] = include_bytes!("fixtures/ping.json");
+    const PING_SHA256: &str =
+        "sha256=5cfe7d638c4c960e2da02a9fb0e5959a892d2e33a925a1dac5fd2ed399c04b65";
+    const PING_SHA1: &str = "sha1=505a347264537225f7d5595d62d7eb0daca4fc0c";
+
+    fn secret(id: &str, secret: &str) -> WebhookSecret {
+        WebhookSecret {
+            id: id.to_string(),
+            secret: secret.to_string(),
+        }
+    }
+
+    async fn status(
+        factory: VerifyGithubSignatureFactory,
+        headers: &[(&str, &str)],
+        body: &'static [u8],
+    ) -> StatusCode {
+        let app = test::init_service(
+            App::new().route(
+                "/push",
+                web::post()
+                    .to(|body: Bytes| async move { body })
+                    .wrap(factory),
+            ),
+        )
+        .await;
+
+        let mut req = test::TestRequest::post().uri("/push").set_payload(body);
+        for header in headers {
+            req = req.insert_header(*header);
+        }
+
+        // Errors of the middleware are turned into responses by the server, not the test service
+        let res = match test::try_call_service(&app, req.to_request()).await {
+            Ok(res) => res,
+            Err(e) => return e.as_response_error().status_code(),
+        };
+        let status = res.status();
+        if status.is_success() {
+            // The handler gets the body that was verified
+            assert_eq!(test::read_body(res).await, body);
+        }
+        status
+    }
+
+    fn factory_for(repository: &str, legacy_sha1: bool) -> VerifyGithubSignatureFactory {
+        VerifyGithubSignatureFactory::new(
+            true,
+            WebhookSecrets {
+                shared: vec![secret("shared", "It's a Secret to Everybody")],
+                repositories: HashMap::from([(
+                    repository.to_string(),
+                    vec![secret("repository", "webhook_secret")],
+                )]),
+            },
+            legacy_sha1,
+            1024,
+        )
+    }
+
+    // The ping fixture is for the repository Chatterino/chatterino2
+    fn factory(legacy_sha1: bool) -> VerifyGithubSignatureFactory {
+        factory_for("chatterino/chatterino2", legacy_sha1)
+    }
+
+    #[actix_web::test]
+    async fn accepts_valid_signatures() {
+        // The example from GitHub's documentation on validating webhook deliveries
+        assert_eq!(
+            status(
+                factory(false),
+                &[(
+                    "x-hub-signature-256",
+                    "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17"
+                )],
+                b"Hello, World!"
+            )
+            .await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(
+                factory(false),
+                &[("x-hub-signature-256", PING_SHA256)],
+                PING
+            )
+            .await,
+            StatusCode::OK
+        );
+    }
+
+    #[actix_web::test]
+    async fn rejects_invalid_signatures() {
+        let mut tampered = PING.to_vec();
+        tampered[10] ^= 1;
+        let tampered: &'static [u8] = tampered.leak();
+
+        assert_eq!(
+            status(
+                factory(false),
+                &[("x-hub-signature-256", PING_SHA256)],
+                tampered
+            )
+            .await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(factory(false), &[], PING).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(
+                factory(false),
+                &[("x-hub-signature-256", "5cfe7d638c4c960e2da02a9fb0e5959a")],
+                PING
+            )
+            .await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[actix_web::test]
+    async fn only_accepts_secrets_of_the_delivery_repository() {
+        assert_eq!(
+            status(
+                factory_for("pajlada/chatterino2", false),
+                &[("x-hub-signature-256", PING_SHA256)],
+                PING
+            )
+            .await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[actix_web::test]
+    async fn accepts_sha1_signatures_in_legacy_mode() {
+        assert_eq!(
+            status(factory(false), &[("x-hub-signature", PING_SHA1)], PING).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(factory(true), &[("x-hub-signature", PING_SHA1)], PING).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(
+                factory(true),
+                &[(
+                    "x-hub-signature",
+                    "sha1=0000000000000000000000000000000000000000"
+                )],
+                PING
+            )
+            .await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[actix_web::test]
+    async fn rejects_large_bodies() {
+        let body: &'static [u8] = vec![b'a'; 2048].leak();
+
+        assert_eq!(
+            status(
+                factory(false),
+                &[("x-hub-signature-256", PING_SHA256)],
+                body
+            )
+            .await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+}
@@ -1,68 +1,96 @@
-use std::sync::Mutex;
-
 use actix_web::{
     guard,
     web::{self, Data},
     App, HttpServer,
 };
-use tokio::task::AbortHandle;
 use tracing_actix_web::TracingLogger;
 
 #[allow(unused)]
 use tracing::log::*;
 
+mod artifacts;
+mod delivery;
 mod middleware;
 mod ping;
+mod pull_request;
 mod push;
 mod span_builder;
+mod trigger;
+
+use self::middleware::{VerifyGithubSignatureFactory, WebhookSecrets};
 
-use self::middleware::VerifyGithubSignatureFactory;
+// GitHub caps webhook payloads at 25 MB
+const DEFAULT_MAX_BODY_SIZE: usize = 25 * 1024 * 1024;
 
 pub async fn start_server(
     cfg: crate::config::Config,
-    pipelines: crate::build::Pipelines,
-    github_client: reqwest::Client,
+    repositories: std::sync::Arc<crate::build::Repositories>,
+    jobs: std::sync::Arc<crate::build::Jobs>,
+    state: std::sync::Arc<crate::build::State>,
 ) -> anyhow::Result<()> {
-    let github_client = Data::new(github_client);
+    let state = Data::from(state);
     let web_cfg = Data::new(cfg.clone());
     let web_base_url = cfg.web.base_url.clone();
-    let pipelines = Data::new(pipelines);
-    let current_job: Data<Mutex<Option<AbortHandle>>> = Data::new(Mutex::new(None));
+    let repositories = Data::from(repositories);
+    let jobs = Data::from(jobs);
 
     if !cfg.github.verify_signature {
         warn!("Github signature verification is disabled");
     }
 
-    let verify_signature =
-        VerifyGithubSignatureFactory::new(cfg.github.verify_signature, &cfg.github.secret).unwrap();
+    let max_body_size = cfg.web.max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE);
+    let legacy_signature = cfg.github.legacy_signature.unwrap_or(false);
+    if cfg.github.verify_signature && legacy_signature {
+        warn!("Legacy SHA-1 signatures are accepted");
+    }
+
+    let verify_signature = VerifyGithubSignatureFactory::new(
+        cfg.github.verify_signature,
+        WebhookSecrets::new(&cfg),
+        legacy_signature,
+        max_body_size,
+    );
 
     let mut server = HttpServer::new(move || {
         let tracing_logger = TracingLogger::<span_builder::SpanBuilder>::new();
 
-        App::new()
-            .app_data(github_client.clone())
+        let app = App::new()
             .app_data(web_cfg.clone())
-            .app_data(pipelines.clone())
-            .app_data(current_job.clone())
-            .wrap(tracing_logger)
+            .app_data(repositories.clone())
+            .app_data(jobs.clone())
+            .app_data(state.clone())
+            // The default limit of 32 KB is too small for the payload of a large push
+            .app_data(web::JsonConfig::default().limit(max_body_size));
+
+        app.wrap(tracing_logger)
             .wrap(actix_web::middleware::Logger::default())
             .service(
                 web::scope(&web_base_url)
                     .route(
                         "/push",
                         web::post()
-                            .wrap(verify_signature.clone())
                             .guard(guard::Header("x-github-event", "push"))
-                            .to(push::on_push),
+                            .to(push::on_push)
+                            .wrap(verify_signature.clone()),
                     )
                     .route(
                         "/push",
                         web::post()
-                            .wrap(verify_signature.clone())
                             .guard(guard::Header("x-github-event", "ping"))
-                            .to(push::on_ping),
+                            .to(push::on_ping)
+                            .wrap(verify_signature.clone()),
+                    )
+                    .route(
+                        "/push",
+                        web::post()
+                            .guard(guard::Header("x-github-event", "pull_request"))
+                            .to(pull_request::on_pull_request)
+                            .wrap(verify_signature.clone()),
                     )
-                    .service(ping::ping),
+                    .route("/build", web::post().to(trigger::on_trigger))
+                    .service(ping::ping)
+                    .service(artifacts::artifact)
+                    .service(artifacts::legacy_artifact),
             )
     });
 
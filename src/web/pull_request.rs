@@ -0,0 +1,96 @@
+use actix_web::{web::Data, web::Json, HttpRequest, HttpResponse};
+
+#[allow(unused)]
+use tracing::log::*;
+
+use crate::build::{self, BuildRequest};
+use crate::github::model::PullRequestEvent;
+
+use super::delivery;
+
+#[tracing::instrument(skip(repositories, jobs, state, req, payload))]
+pub async fn on_pull_request(
+    repositories: Data<build::Repositories>,
+    jobs: Data<build::Jobs>,
+    state: Data<build::State>,
+    req: HttpRequest,
+    payload: Json<PullRequestEvent>,
+) -> actix_web::Result<actix_web::HttpResponse> {
+    info!("On pull request");
+
+    let delivery_id = delivery::delivery_id(&req);
+    if let Some(response) = delivery::acknowledge_duplicate(&state, delivery_id.as_deref()) {
+        return Ok(response);
+    }
+
+    let Some(repository) = repositories.get(&payload.repository.full_name) else {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "Pull request event is for the unknown repo '{}'",
+            payload.repository.full_name
+        )));
+    };
+
+    let Some(pull_requests) = &repository.pull_requests else {
+        return Ok(HttpResponse::Ok().body(format!(
+            "Pull request builds are disabled for {}",
+            repository.full_name
+        )));
+    };
+
+    if payload.action == "closed" {
+        info!("Cleaning up pull request #{}", payload.number);
+
+        jobs.abort(&pull_requests.repo_dir(payload.number));
+        pull_requests
+            .close(payload.number)
+            .await
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+
+        return Ok(HttpResponse::Ok().body(format!("Cleaned up pull request #{}", payload.number)));
+    }
+
+    // e.g. a label can be added to a closed pull request
+    if payload.pull_request.state != "open" {
+        return Ok(HttpResponse::Ok().body(format!(
+            "Ignoring pull request #{}, it's {}",
+            payload.number, payload.pull_request.state
+        )));
+    }
+
+    if !matches!(
+        payload.action.as_str(),
+        "opened" | "synchronize" | "labeled"
+    ) {
+        return Ok(
+            HttpResponse::Ok().body(format!("Ignoring pull request action {}", payload.action))
+        );
+    }
+
+    if !pull_requests.is_allowed(&payload) {
+        info!(
+            "Not building pull request #{} by {}",
+            payload.number, payload.pull_request.user.login
+        );
+        return Ok(HttpResponse::Ok().body(format!(
+            "Pull request #{} is not allowed to be built",
+            payload.number
+        )));
+    }
+
+    let pipelines = pull_requests.pipelines(&payload);
+    let num_pipelines = pipelines.len();
+
+    jobs.spawn(
+        pipelines,
+        BuildRequest {
+            commit: Some(payload.pull_request.head.sha.clone()),
+            delivery_id,
+            ..Default::default()
+        },
+    );
+
+    Ok(HttpResponse::Ok().body(format!(
+        "Spun up {num_pipelines} builds of pull request #{}",
+        payload.number
+    )))
+}
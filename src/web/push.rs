@@ -1,33 +1,63 @@
-use std::sync::Mutex;
+use actix_web::{web::Data, web::Json, HttpRequest, HttpResponse};
 
-use actix_web::{web::Data, web::Json, HttpResponse};
-
-use tokio::task::AbortHandle;
 #[allow(unused)]
 use tracing::log::*;
 
-use crate::config;
+use crate::build::{self, BuildRequest};
 use crate::github;
 
-#[tracing::instrument(skip(cfg, pipelines, current_job, payload))]
+use super::delivery;
+
+// Push webhooks list at most this many commits, the ones after them are left out
+const MAX_PAYLOAD_COMMITS: usize = 2048;
+
+/// Returns the files changed by the push, if the payload lists all of its commits along with
+/// their changed files. Otherwise they're looked up in the clone
+fn changed_files(payload: &github::model::Root) -> Option<Vec<String>> {
+    // A forced push can drop files of commits that aren't listed
+    if payload.forced || payload.commits.is_empty() || payload.commits.len() >= MAX_PAYLOAD_COMMITS
+    {
+        return None;
+    }
+
+    let mut files = vec![];
+    for commit in &payload.commits {
+        // Commits may be listed without their files
+        let (Some(added), Some(removed), Some(modified)) =
+            (&commit.added, &commit.removed, &commit.modified)
+        else {
+            return None;
+        };
+
+        files.extend(added.iter().chain(removed).chain(modified).cloned());
+    }
+    files.sort();
+    files.dedup();
+
+    Some(files)
+}
+
+#[tracing::instrument(skip(repositories, jobs, state, req, payload))]
 pub async fn on_push(
-    cfg: Data<config::Config>,
-    pipelines: Data<crate::build::Pipelines>,
-    current_job: Data<Mutex<Option<AbortHandle>>>,
+    repositories: Data<build::Repositories>,
+    jobs: Data<build::Jobs>,
+    state: Data<build::State>,
+    req: HttpRequest,
     payload: Json<github::model::Root>,
 ) -> actix_web::Result<actix_web::HttpResponse> {
     info!("On push");
 
-    let repo_owner = cfg.github.repo_owner.clone();
-    let repo_name = cfg.github.repo_name.clone();
-    let repo_full_name = format!("{}/{}", repo_owner, repo_name);
+    let delivery_id = delivery::delivery_id(&req);
+    if let Some(response) = delivery::acknowledge_duplicate(&state, delivery_id.as_deref()) {
+        return Ok(response);
+    }
 
-    if payload.repository.full_name != repo_full_name {
+    let Some(repository) = repositories.get(&payload.repository.full_name) else {
         return Err(actix_web::error::ErrorBadRequest(format!(
-            "Push event is not for the correct repo '{}",
-            repo_full_name
+            "Push event is for the unknown repo '{}'",
+            payload.repository.full_name
         )));
-    }
+    };
 
     let stripped_branch_name = match payload.push_ref.strip_prefix("refs/heads/") {
         Some(stripped_branch_name) => stripped_branch_name,
@@ -40,36 +70,66 @@ pub async fn on_push(
         }
     };
 
-    match pipelines.get(stripped_branch_name) {
+    // A push that deletes the branch has no commit to build, sent as all zeroes
+    if payload.head_commit.is_none() && payload.after.bytes().all(|b| b == b'0') {
+        repository.forget_branch(stripped_branch_name);
+        return Ok(
+            HttpResponse::Ok().body(format!("Ignoring the deletion of {stripped_branch_name}"))
+        );
+    }
+
+    match repository.pipelines(stripped_branch_name) {
         Some(pipelines) => {
-            let pipelines = pipelines.clone();
             if pipelines.is_empty() {
                 info!("No push events registered for {stripped_branch_name}");
                 return Ok(HttpResponse::Ok()
                     .body(format!("The branch {stripped_branch_name} is not handled")));
             }
 
-            let num_pipelines = pipelines.len();
+            let directives = build::Directives::parse(
+                payload
+                    .head_commit
+                    .as_ref()
+                    .map_or("", |commit| commit.message.as_str()),
+            );
+            let pipelines = directives.filter(pipelines);
 
-            let handle = tokio::spawn(async move {
-                for p in pipelines {
-                    let res = p.build().await;
+            if pipelines.is_empty() {
+                let reason = if directives.skip {
+                    "the head commit's message skips all builds".to_string()
+                } else {
+                    format!("no pipeline is named {}", directives.build.join(", "))
+                };
+                info!("Not building {stripped_branch_name}: {reason}");
+                return Ok(HttpResponse::Ok().body(format!("Not building: {reason}")));
+            }
 
-                    if let Err(e) = res {
-                        info!("Error building/uploading asset: {e:?}");
-                    }
+            let num_pipelines = pipelines.len();
+            let request = BuildRequest {
+                commit: Some(payload.after.clone()),
+                // A push that creates the branch has no previous commit, sent as all zeroes
+                before: Some(payload.before.clone())
+                    .filter(|before| before.bytes().any(|b| b != b'0')),
+                delivery_id,
+                changed_files: changed_files(&payload),
+                force: !directives.build.is_empty(),
+                ..Default::default()
+            };
+
+            // Otherwise the next poll sees the pushed head as moved and builds it again
+            let polled = repository.poll_interval.is_some();
+            let (state, full_name, branch, commit) = (
+                state.into_inner(),
+                repository.full_name.clone(),
+                stripped_branch_name.to_string(),
+                payload.after.clone(),
+            );
+            jobs.spawn_all_then(vec![(pipelines, request)], move |_| {
+                if polled {
+                    state.set_branch_head(&full_name, &branch, &commit);
                 }
             });
 
-            let abort_handle = handle.abort_handle();
-
-            let old_abort_handle = current_job.lock().unwrap().replace(abort_handle);
-
-            if let Some(old_abort_handle) = old_abort_handle {
-                info!("Aborting old job");
-                old_abort_handle.abort();
-            }
-
             Ok(HttpResponse::Ok().body(format!("Spun up {num_pipelines} builds")))
         }
         None => Ok(
@@ -84,3 +144,206 @@ pub async fn on_ping() -> actix_web::Result<actix_web::HttpResponse> {
 
     Ok(HttpResponse::Ok().body("pong"))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::{test as actix_test, web, App};
+
+    use super::*;
+
+    fn repositories(
+        dir: &std::path::Path,
+        state: Arc<build::State>,
+        repository_lines: &str,
+    ) -> build::Repositories {
+        crate::git::testing::commit(
+            &crate::git::testing::init(&dir.join("owner/repo")),
+            &[("CMakeLists.txt", Some("project(chatterino)"))],
+            "Initial commit",
+        );
+
+        let path = dir.join("config.toml");
+        std::fs::write(
+            &path,
+            format!(
+                r#"
+[web]
+bind = ["127.0.0.1:0"]
+
+[github]
+token = "test-token"
+api_base_url = "http://127.0.0.1:1"
+web_base_url = "file://{dir}"
+verify_signature = false
+
+[build]
+dmg_output_path = "chatterino.dmg"
+state_dir = "{dir}/state"
+
+[[repositories]]
+owner = "owner"
+name = "repo"
+repo_dir = "{dir}/clone"
+branches = [{{ name = "master", release_tag = "nightly-build" }}]
+{repository_lines}
+
+[[repositories.configs]]
+cmake_args = []
+package_envs = []
+pre_cmake_commands = ["sleep 5"]
+build_dir = "build"
+asset_name = "Chatterino.dmg"
+"#,
+                dir = dir.display(),
+            ),
+        )
+        .unwrap();
+
+        let cfg = crate::config::read(path.to_str().unwrap()).unwrap();
+        let repository = &cfg.repositories[0];
+        let client =
+            github::GithubClient::new(&cfg.github, &repository.owner, &repository.name).unwrap();
+
+        build::Repositories::new(vec![build::Repository::new(
+            &cfg, repository, client, state,
+        )
+        .unwrap()])
+    }
+
+    #[test]
+    fn lists_changed_files_of_complete_payloads() {
+        let commit = |files: Option<&[&str]>| github::model::Commit {
+            added: Some(vec![]),
+            removed: Some(vec!["docs/old.md".to_string()]),
+            modified: files.map(|files| files.iter().map(|f| f.to_string()).collect()),
+            ..Default::default()
+        };
+        let mut payload = github::model::Root {
+            commits: vec![
+                commit(Some(&["src/main.cpp", "README.md"])),
+                commit(Some(&["src/main.cpp"])),
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            changed_files(&payload),
+            Some(vec![
+                "README.md".to_string(),
+                "docs/old.md".to_string(),
+                "src/main.cpp".to_string(),
+            ])
+        );
+
+        payload.commits.push(commit(None));
+        assert_eq!(changed_files(&payload), None);
+
+        payload.commits = vec![commit(Some(&[])); MAX_PAYLOAD_COMMITS];
+        assert_eq!(changed_files(&payload), None);
+
+        payload.commits = vec![commit(Some(&[]))];
+        payload.forced = true;
+        assert_eq!(changed_files(&payload), None);
+    }
+
+    #[actix_web::test]
+    async fn acknowledges_redeliveries_without_building() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = Arc::new(build::State::open(&dir.path().join("state")).unwrap());
+        let repositories = web::Data::new(repositories(dir.path(), state.clone(), ""));
+        let jobs = web::Data::new(build::Jobs::default());
+        let repo_dir = dir.path().join("clone");
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(repositories)
+                .app_data(jobs.clone())
+                .app_data(web::Data::from(state))
+                .route("/push", web::post().to(on_push)),
+        )
+        .await;
+
+        let payload = github::model::Root {
+            push_ref: "refs/heads/master".to_string(),
+            after: "0123456789abcdef0123456789abcdef01234567".to_string(),
+            head_commit: Some(github::model::Commit {
+                message: "Fix the build".to_string(),
+                ..Default::default()
+            }),
+            repository: github::model::Repository {
+                full_name: "owner/repo".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let push = || {
+            actix_test::TestRequest::post()
+                .uri("/push")
+                .insert_header(("x-github-delivery", "delivery-1"))
+                .set_json(&payload)
+                .to_request()
+        };
+
+        let body = actix_test::call_and_read_body(&app, push()).await;
+        assert_eq!(body, "Spun up 1 builds");
+        assert!(jobs.is_running(&repo_dir));
+        jobs.abort(&repo_dir);
+
+        let body = actix_test::call_and_read_body(&app, push()).await;
+        assert_eq!(body, "Delivery delivery-1 was already received");
+        assert!(!jobs.is_running(&repo_dir));
+    }
+
+    #[actix_web::test]
+    async fn records_built_heads_of_polled_repositories() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = Arc::new(build::State::open(&dir.path().join("state")).unwrap());
+        let repositories = web::Data::new(repositories(
+            dir.path(),
+            state.clone(),
+            "poll_interval_seconds = 300",
+        ));
+        let jobs = web::Data::new(build::Jobs::default());
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(repositories)
+                .app_data(jobs.clone())
+                .app_data(web::Data::from(state.clone()))
+                .route("/push", web::post().to(on_push)),
+        )
+        .await;
+
+        // The commit doesn't exist, so the build fails without running the commands
+        let payload = github::model::Root {
+            push_ref: "refs/heads/master".to_string(),
+            after: "0123456789abcdef0123456789abcdef01234567".to_string(),
+            repository: github::model::Repository {
+                full_name: "owner/repo".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let request = actix_test::TestRequest::post()
+            .uri("/push")
+            .set_json(&payload)
+            .to_request();
+
+        let body = actix_test::call_and_read_body(&app, request).await;
+        assert_eq!(body, "Spun up 1 builds");
+
+        let repo_dir = dir.path().join("clone");
+        while jobs.is_running(&repo_dir) {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+
+        assert_eq!(
+            state
+                .branch_heads
+                .read(|heads| heads.get("owner/repo/master").cloned()),
+            Some(payload.after)
+        );
+    }
+}
@@ -14,6 +14,11 @@ impl RootSpanBuilder for SpanBuilder {
             Level::WARN,
             "request",
             path = request.path(),
+            delivery_id = request
+                .headers()
+                .get("x-github-delivery")
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("none"),
             peer = request.connection_info().peer_addr().unwrap_or("unknown")
         );
 
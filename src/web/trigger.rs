@@ -0,0 +1,105 @@
+use actix_web::{web::Data, web::Json, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+
+#[allow(unused)]
+use tracing::log::*;
+
+use crate::build::{self, BuildRequest};
+use crate::config;
+
+#[derive(Debug, Deserialize)]
+pub struct TriggerRequest {
+    // owner/name, can be left out if only one repository is configured
+    pub repository: Option<String>,
+
+    pub branch: String,
+
+    // Wipe the build directory before building. Manual builds are clean unless this is false
+    #[serde(default = "default_clean")]
+    pub clean: bool,
+}
+
+fn default_clean() -> bool {
+    true
+}
+
+fn is_authorized(cfg: &config::Config, req: &HttpRequest) -> bool {
+    let Some(trigger_token) = &cfg.web.trigger_token else {
+        return false;
+    };
+
+    req.headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        // Compared in constant time, so the token can't be guessed from response times
+        .is_some_and(|token| bool::from(token.as_bytes().ct_eq(trigger_token.as_bytes())))
+}
+
+#[tracing::instrument(skip(cfg, repositories, jobs, req))]
+pub async fn on_trigger(
+    cfg: Data<config::Config>,
+    repositories: Data<build::Repositories>,
+    jobs: Data<build::Jobs>,
+    req: HttpRequest,
+    payload: Json<TriggerRequest>,
+) -> actix_web::Result<actix_web::HttpResponse> {
+    if !is_authorized(&cfg, &req) {
+        return Err(actix_web::error::ErrorUnauthorized("invalid trigger token"));
+    }
+
+    let repository = match &payload.repository {
+        Some(full_name) => repositories.get(full_name).ok_or_else(|| {
+            actix_web::error::ErrorNotFound(format!("The repository {full_name} is not handled"))
+        })?,
+        None => repositories.single().ok_or_else(|| {
+            actix_web::error::ErrorBadRequest(
+                "The repository must be given if more than one is configured",
+            )
+        })?,
+    };
+
+    let Some(pipelines) = repository.pipelines(&payload.branch) else {
+        return Err(actix_web::error::ErrorNotFound(format!(
+            "The branch {} of {} is not handled",
+            payload.branch, repository.full_name
+        )));
+    };
+
+    info!(
+        "Manually triggered build of {} of {} (clean: {})",
+        payload.branch, repository.full_name, payload.clean
+    );
+
+    let num_pipelines = pipelines.len();
+
+    jobs.spawn(
+        pipelines,
+        BuildRequest {
+            clean: payload.clean,
+            ..Default::default()
+        },
+    );
+
+    Ok(HttpResponse::Ok().body(format!("Spun up {num_pipelines} builds")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_clean_by_default() {
+        let request: TriggerRequest = serde_json::from_str(r#"{"branch": "master"}"#).unwrap();
+        assert!(request.clean);
+        assert_eq!(request.repository, None);
+
+        let request: TriggerRequest = serde_json::from_str(
+            r#"{"repository": "owner/repo", "branch": "master", "clean": false}"#,
+        )
+        .unwrap();
+        assert!(!request.clean);
+        assert_eq!(request.repository.as_deref(), Some("owner/repo"));
+    }
+}